@@ -0,0 +1,16 @@
+#include <stdlib.h>
+
+#define CAPACITY 16
+
+struct stack {
+  int items[CAPACITY];
+  size_t len;
+};
+
+int push(struct stack *s, int item) {
+  if (s->len >= CAPACITY) {
+    return -1;
+  }
+  s->items[s->len++] = item;
+  return 0;
+}
@@ -0,0 +1,21 @@
+#include <memory>
+#include <vector>
+
+namespace shapes {
+
+class Shape {
+public:
+  virtual ~Shape() = default;
+  virtual double area() const = 0;
+};
+
+template <typename T>
+T total_area(const std::vector<std::unique_ptr<Shape>> &shapes) {
+  T sum = 0;
+  for (const auto &shape : shapes) {
+    sum += shape->area();
+  }
+  return sum;
+}
+
+} // namespace shapes
@@ -0,0 +1,15 @@
+package greet
+
+import "fmt"
+
+type Greeter struct {
+	Name string
+}
+
+func (g *Greeter) Greet(names ...string) []string {
+	out := make([]string, 0, len(names))
+	for _, name := range names {
+		out = append(out, fmt.Sprintf("%s says hi to %s", g.Name, name))
+	}
+	return out
+}
@@ -0,0 +1,17 @@
+package example;
+
+import java.util.HashMap;
+import java.util.Map;
+
+public class Counter {
+    private final Map<String, Integer> counts = new HashMap<>();
+
+    public int increment(String key) {
+        return counts.merge(key, 1, Integer::sum);
+    }
+
+    @Override
+    public String toString() {
+        return "Counter" + counts;
+    }
+}
@@ -0,0 +1,16 @@
+import { formatPrice } from "./format";
+
+export class Cart {
+  #items = [];
+
+  add(item, quantity = 1) {
+    this.#items.push({ ...item, quantity });
+    return this;
+  }
+
+  get total() {
+    return this.#items.reduce((sum, { price, quantity }) => sum + price * quantity, 0);
+  }
+}
+
+export const describe = (cart) => `total: ${formatPrice(cart.total)}`;
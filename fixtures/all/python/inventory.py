@@ -0,0 +1,12 @@
+from dataclasses import dataclass, field
+
+
+@dataclass
+class Inventory:
+    items: dict[str, int] = field(default_factory=dict)
+
+    def add(self, name: str, count: int = 1) -> None:
+        self.items[name] = self.items.get(name, 0) + count
+
+    def low_stock(self, threshold=3):
+        return [name for name, count in self.items.items() if count < threshold]
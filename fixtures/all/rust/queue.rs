@@ -0,0 +1,19 @@
+use std::collections::VecDeque;
+
+#[derive(Debug, Default)]
+pub struct Queue<T> {
+    items: VecDeque<T>,
+}
+
+impl<T: Clone> Queue<T> {
+    pub fn push(&mut self, item: T) {
+        self.items.push_back(item);
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        match self.items.pop_front() {
+            Some(item) => Some(item),
+            None => None,
+        }
+    }
+}
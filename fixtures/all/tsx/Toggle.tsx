@@ -0,0 +1,14 @@
+import { useState } from "react";
+
+interface Props {
+  label: string;
+}
+
+export function Toggle({ label }: Props) {
+  const [on, setOn] = useState<boolean>(false);
+  return (
+    <button onClick={() => setOn(!on)}>
+      {label}: {on ? "on" : "off"}
+    </button>
+  );
+}
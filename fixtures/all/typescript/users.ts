@@ -0,0 +1,20 @@
+export type Role = "admin" | "member";
+
+export interface User {
+  id: number;
+  name: string;
+  role?: Role;
+}
+
+export enum Status {
+  Active,
+  Suspended,
+}
+
+export class Users {
+  private readonly byId = new Map<number, User>();
+
+  find(id: number): User | undefined {
+    return this.byId.get(id);
+  }
+}
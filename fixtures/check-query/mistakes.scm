@@ -0,0 +1,15 @@
+; functions that don't take `self`
+(function_item
+  name: (identifier) @name
+  !arguments)
+
+; a function body is always a block
+(function_item
+  body: [(block) (integer_literal)] @body)
+
+; @_macro is never output, and nothing tests it
+(macro_invocation
+  macro: (identifier) @_macro
+  (token_tree) @tokens)
+
+(function_item name: (identifier) @name !arguments)
@@ -0,0 +1,2 @@
+# vim: set ft=rust :
+print("this is python, whatever the modeline says")
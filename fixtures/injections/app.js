@@ -0,0 +1,7 @@
+const script = python`
+def helper():
+    return len([])
+
+def main():
+    print(helper())
+`;
@@ -0,0 +1,11 @@
+; calls to `.unwrap()`, which panic instead of handling the error
+(call_expression
+  function: (field_expression
+    field: (field_identifier) @_method)
+  (#eq? @_method "unwrap")) @call
+
+; and `.expect()`, which does too
+(call_expression
+  function: (field_expression
+    field: (field_identifier) @_method)
+  (#eq? @_method "expect")) @call
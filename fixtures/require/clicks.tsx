@@ -0,0 +1,5 @@
+import React from "react";
+
+export function Clicks({ count }: { count: number }) {
+  return <span>{count} clicks</span>;
+}
@@ -0,0 +1,9 @@
+import React, { useEffect, useState } from "react";
+
+export function Counter() {
+  const [count, setCount] = useState(0);
+  useEffect(() => {
+    document.title = `${count} clicks`;
+  });
+  return <button onClick={() => setCount(count + 1)}>{count}</button>;
+}
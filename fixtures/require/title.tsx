@@ -0,0 +1,8 @@
+import { useEffect } from "preact/hooks";
+
+export function Title({ text }: { text: string }) {
+  useEffect(() => {
+    document.title = text;
+  }, [text]);
+  return null;
+}
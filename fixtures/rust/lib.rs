@@ -0,0 +1,33 @@
+use std::collections::HashMap;
+
+/// Counts how often each word appears.
+pub fn word_counts(text: &str) -> HashMap<&str, usize> {
+    let mut counts = HashMap::new();
+    for word in text.split_whitespace() {
+        *counts.entry(word).or_insert(0) += 1;
+    }
+    counts
+}
+
+// TODO: handle punctuation
+pub fn most_common(text: &str) -> Option<&str> {
+    let counts = word_counts(text);
+    let (word, _) = counts.into_iter().max_by_key(|(_, count)| *count)?;
+    Some(word)
+}
+
+pub fn parse_number(raw: &str) -> i64 {
+    raw.trim().parse().unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_words() {
+        // FIXME: this doesn't check the order
+        let counts = word_counts("a b a");
+        assert_eq!(*counts.get("a").unwrap(), 2);
+    }
+}
@@ -1,22 +1,34 @@
-use crate::extractor::Extractor;
+use crate::config::{self, Config};
+use crate::extractor::{CaptureFilter, Extractor};
 use crate::extractor_chooser::ExtractorChooser;
-use crate::language::Language;
+use crate::file_types::FileTypes;
+use crate::grammar_builder::GrammarBuilder;
+use crate::language::{Aliases, Language, Registry};
+use crate::pattern;
+use crate::scope;
 use anyhow::{bail, Context, Error, Result};
 use clap::{crate_authors, crate_version, Arg, ArgMatches, Command};
 use itertools::Itertools;
+use regex::Regex;
 use std::collections::HashMap;
-use std::path::{PathBuf, Path};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 pub enum Invocation {
     DoQuery(QueryOpts),
-    ShowLanguages,
+    ShowLanguages(LanguagesOpts),
     ShowTree(TreeOpts),
+    ShowNodeTypes(NodeTypesOpts),
+    CheckQueries(CheckQueriesOpts),
+    BuildGrammar(GrammarBuilder),
 }
 
 #[derive(Debug)]
 pub struct QueryOpts {
     pub extractors: Vec<Extractor>,
+    pub file_types: FileTypes,
+    pub aliases: Aliases,
     pub paths: Vec<PathBuf>,
     pub git_ignore: bool,
     pub format: QueryFormat,
@@ -28,53 +40,268 @@ pub struct QueryOpts {
     pub show_count: bool,
     pub no_filename: bool,
     pub no_line_number: bool,
+    pub show_query: bool,
+    pub group: bool,
+    /// Stop once we've found this many matches across every file.
+    pub max_results: Option<usize>,
+    pub quiet: bool,
+    pub keep_going: bool,
+}
+
+/// A LANGUAGE and QUERY given without -q.
+type PositionalQuery<'a> = Option<(&'a str, &'a str)>;
+
+/// Every query for a language combined into one, with the label and `--in`
+/// regex for each of its patterns.
+type CombinedQuery = (Language, String, Vec<String>, Vec<Option<Regex>>);
+
+/// A query to run, before we combine it with the others for its language.
+#[derive(Debug)]
+pub struct QuerySource {
+    pub language: Language,
+    pub source: String,
+    /// What to call the query in the output. This is `None` until we've
+    /// decided on a default.
+    pub label: Option<String>,
+    /// For `--in`, the regex to look for inside everything the query
+    /// captures.
+    pub regex: Option<Regex>,
 }
 
 impl QueryOpts {
-    pub fn extractor_chooser(&self) -> Result<ExtractorChooser> {
-        ExtractorChooser::from_extractors(&self.extractors)
+    pub fn extractor_chooser(&self) -> Result<ExtractorChooser<'_>> {
+        ExtractorChooser::from_extractors(&self.extractors, &self.file_types, &self.aliases)
     }
 }
 
+#[derive(Debug)]
+pub struct LanguagesOpts {
+    pub registry: Registry,
+    pub file_types: FileTypes,
+    pub format: QueryFormat,
+}
+
 #[derive(Debug)]
 pub struct TreeOpts {
     pub language: Language,
     pub path: PathBuf,
 }
 
+#[derive(Debug)]
+pub struct CheckQueriesOpts {
+    pub queries: Vec<QuerySource>,
+    pub shown_captures: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct NodeTypesOpts {
+    pub language: Language,
+    pub format: QueryFormat,
+}
+
 impl Invocation {
     pub fn from_args(args: Vec<String>) -> Result<Self> {
-        // I'm not super happy with this! I would love for LANGUAGE and QUERY to
-        // be taken positionally when there is just one so we don't always have
-        // to specify `-q`. However, I also want to get working on the rest of
-        // the program so I'm dropping the requirement for now by making `-q`
-        // required. I think that's an OK tradeoff until I can figure something
-        // else better because it'll be backwards compatible with the scheme
-        // I outlined above.
-        //
-        // Check
+        // LANGUAGE and QUERY can be given positionally when there's just one,
+        // like `tree-grepper rust '(function_item)' src/`. Clap can't express
+        // "the first two positionals are special unless -q was given" (see
         // https://users.rust-lang.org/t/grep-like-argument-parsing-with-clap/63392
-        // for where I asked about this in public.
+        // where I asked about this in public) so we take everything as PATHS
+        // and sort it out ourselves in `positional_query`. The rule is simple
+        // enough to explain: if there's no -q (or --pattern, --in,
+        // --query-file, or --query-name), the first two positionals are the
+        // language and query.
         let matches = Command::new("tree-grepper")
             .version(crate_version!())
             .author(crate_authors!())
+            .override_usage(
+                "tree-grepper [OPTIONS] LANGUAGE QUERY [PATHS]...
+    tree-grepper [OPTIONS] -q LANGUAGE QUERY... [PATHS]...
+    tree-grepper grammar build [OPTIONS] DIR",
+            )
             .arg(
                 Arg::new("additional-query")
                     .short('q')
                     .long("query")
                     .help("a language and query to perform")
                     .long_help(
-                        "a language and query to perform (at least one is required.) See https://tree-sitter.github.io for information on writing queries. Run tree-grepper --languages for a list of languages.",
+                        "a language and query to perform. May be given more than once. When there's only one, you can leave off the -q and give LANGUAGE and QUERY as the first two positional arguments instead. See https://tree-sitter.github.io for information on writing queries. Run tree-grepper --languages for a list of languages.
+
+Queries can use these predicates, and a #not- version of each (like #not-any-of?):
+
+  #eq? @capture \"text\"             the capture's text is exactly \"text\" (or another capture's text)
+  #match? @capture \"regex\"         the capture's text matches the regex
+  #any-of? @capture \"a\" \"b\"...     the capture's text is exactly one of the strings
+  #contains? @capture \"a\" \"b\"...   the capture's text contains one of the strings
+  #lua-match? @capture \"pattern\"   the capture's text matches the Lua pattern
+  #has-ancestor? @capture kind...  the capture is inside a node of one of these kinds
+  #has-parent? @capture kind...    the capture's parent is a node of one of these kinds",
                     )
                     .number_of_values(2)
                     .value_names(&["LANGUAGE", "QUERY"])
-                    .required_unless_present("languages")
-                    .required_unless_present("show-tree")
                     .conflicts_with("languages")
                     .conflicts_with("show-tree")
                     .multiple_values(true)
                     .multiple_occurrences(true)
             )
+            .arg(
+                Arg::new("pattern")
+                    .long("pattern")
+                    .help("a language and a code snippet to search for")
+                    .long_help("a language and a code snippet to search for, like `--pattern rust 'foo($A, $$$)'`. The snippet is parsed with the language's grammar and turned into a query that matches code with the same structure, ignoring whitespace and comments. In the snippet, $NAME matches any one node and captures it as @NAME (and if the same $NAME appears more than once, each place has to have the same text), $_ matches any one node without capturing it, and $$$ (or $$$_NAME) matches any number of nodes, including none, without capturing them. May be given more than once, and combined with -q.")
+                    .number_of_values(2)
+                    .value_names(&["LANGUAGE", "PATTERN"])
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+                    .multiple_values(true)
+                    .multiple_occurrences(true)
+            )
+            .arg(
+                Arg::new("in")
+                    .long("in")
+                    .help("a language, a kind of node, and a regex to search for inside nodes of that kind")
+                    .long_help("a language, a kind of node, and a regex to search for inside nodes of that kind, like `--in rust comment 'TODO|FIXME'`. Each place the regex matches is reported at its exact position, and the capture is named after the kind. If the grammar doesn't have a kind with exactly that name, every kind with it as a word in its name is used instead (so `comment` means `line_comment` and `block_comment` in Rust.) Instead of a kind, you can give a query, and the regex is searched for in everything it captures. May be given more than once, and combined with -q.")
+                    .number_of_values(3)
+                    .value_names(&["LANGUAGE", "SCOPE", "REGEX"])
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+                    .multiple_values(true)
+                    .multiple_occurrences(true)
+            )
+            .arg(
+                Arg::new("inside")
+                    .long("inside")
+                    .help("only keep matches inside something this query captures")
+                    .long_help("a language and a query. Only matches inside something the query captures are kept, like `--inside rust '(function_item) @function'` for matches in function bodies. Captures starting with an underscore don't count, so they can be used in predicates. The query is run on files of LANGUAGE, and applies to everything found in them (including code injected with --injections.) It's also run on code injected as LANGUAGE into other files, and applies to what's found there. When given more than once, matches have to be inside something from each query.")
+                    .number_of_values(2)
+                    .value_names(&["LANGUAGE", "QUERY"])
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+                    .multiple_values(true)
+                    .multiple_occurrences(true)
+            )
+            .arg(
+                Arg::new("not-inside")
+                    .long("not-inside")
+                    .help("drop matches inside something this query captures")
+                    .long_help("a language and a query. Matches inside something the query captures are dropped, like `--not-inside rust '((attribute_item) @_attr . (mod_item) @module (#eq? @_attr \"#[cfg(test)]\"))'` to leave out test modules. Works like --inside otherwise, and can be combined with it. When given more than once, matches can't be inside something from any of the queries.")
+                    .number_of_values(2)
+                    .value_names(&["LANGUAGE", "QUERY"])
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+                    .multiple_values(true)
+                    .multiple_occurrences(true)
+            )
+            .arg(
+                Arg::new("require")
+                    .long("require")
+                    .help("only search files where this query matches")
+                    .long_help("a language and a query. Only files of LANGUAGE where the query matches somewhere are searched, like `--require typescript '(import_clause (identifier) @name (#eq? @name \"React\"))'` for files that import React. The query is checked against the file's own code, not code injected into it with --injections. Code injected as LANGUAGE into other files is only searched when the query matches somewhere in that code. When given more than once, every query has to match. Can be combined with --forbid.")
+                    .number_of_values(2)
+                    .value_names(&["LANGUAGE", "QUERY"])
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+                    .multiple_values(true)
+                    .multiple_occurrences(true)
+            )
+            .arg(
+                Arg::new("forbid")
+                    .long("forbid")
+                    .help("only search files where this query doesn't match")
+                    .long_help("a language and a query. Only files of LANGUAGE where the query doesn't match anywhere are searched. Works like --require otherwise, and can be combined with it to find files that have one thing but not another. When given more than once, none of the queries can match.")
+                    .number_of_values(2)
+                    .value_names(&["LANGUAGE", "QUERY"])
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+                    .multiple_values(true)
+                    .multiple_occurrences(true)
+            )
+            .arg(
+                Arg::new("query-file")
+                    .long("query-file")
+                    .help("a language and a file containing a query to perform")
+                    .long_help("a language and a file containing a query to perform, like `--query-file rust unwraps.scm`. The file can hold as many patterns (and comments) as you like. May be given more than once, and combined with -q.")
+                    .number_of_values(2)
+                    .value_names(&["LANGUAGE", "PATH"])
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+                    .multiple_values(true)
+                    .multiple_occurrences(true)
+            )
+            .arg(
+                Arg::new("query-name")
+                    .long("query-name")
+                    .help("a language and the name of a query in your query library")
+                    .long_help("a language and the name of a query in your query library. `--query-name rust unwraps` runs the first rust/unwraps.scm found under `query-dirs` in the config file, then tree-grepper/queries under $XDG_CONFIG_HOME, $XDG_DATA_HOME, and $XDG_DATA_DIRS, then a queries directory next to each grammar directory. May be given more than once, and combined with -q and --query-file.")
+                    .number_of_values(2)
+                    .value_names(&["LANGUAGE", "NAME"])
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+                    .multiple_values(true)
+                    .multiple_occurrences(true)
+            )
+            .arg(
+                Arg::new("label")
+                    .long("label")
+                    .takes_value(true)
+                    .value_name("NAME")
+                    .help("what to call the query right before this in the output")
+                    .long_help("what to call the query given right before this (with -q, --pattern, --in, --query-file, or --query-name) in the output, like `-q rust '(macro_invocation)' --label macros`. Without a label, queries from --query-file are called by their file name, queries from --query-name by their name, and -q queries, patterns, and --in searches by their position on the command line (starting at 1.) JSON output always includes the label, and the other formats include it when there's more than one query or any query has a label.")
+                    .multiple_occurrences(true)
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+            )
+            .arg(
+                Arg::new("capture")
+                    .long("capture")
+                    .takes_value(true)
+                    .value_name("NAME")
+                    .help("only output captures with this name")
+                    .long_help("only output captures with this name, like `--capture name` for @name. May be given more than once. This is how to see a capture that starts with an underscore without editing the query. Captures you don't output can still be used in predicates.")
+                    .multiple_occurrences(true)
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+            )
+            .arg(
+                Arg::new("exclude-capture")
+                    .long("exclude-capture")
+                    .takes_value(true)
+                    .value_name("NAME")
+                    .help("don't output captures with this name")
+                    .long_help("don't output captures with this name, like `--exclude-capture body` for @body. May be given more than once. Captures you don't output can still be used in predicates.")
+                    .multiple_occurrences(true)
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+            )
+            .arg(
+                Arg::new("grammar-dir")
+                    .long("grammar-dir")
+                    .takes_value(true)
+                    .value_name("DIR")
+                    .help("a directory to look for grammar libraries in")
+                    .long_help("a directory to look for grammar libraries (like rust.so) in. May be given more than once; directories are searched in order, and the first one containing a matching library wins. These are searched before the directories in $TREE_GREPPER_GRAMMAR_PATH, then `grammar-dirs` in $XDG_CONFIG_HOME/tree-grepper/config.toml, then tree-grepper/grammars under $XDG_DATA_HOME and $XDG_DATA_DIRS.")
+                    .multiple_occurrences(true)
+            )
+            .arg(
+                Arg::new("type-add")
+                    .long("type-add")
+                    .takes_value(true)
+                    .value_name("LANGUAGE:GLOB")
+                    .help("search files matching GLOB with LANGUAGE's queries")
+                    .long_help("search files matching GLOB with LANGUAGE's queries, in addition to the files tree-grepper already knows about (for example `--type-add lua:*.rockspec`.) May be given more than once. You can also set these in the `[file-types]` table of the config file.")
+                    .multiple_occurrences(true)
+                    .conflicts_with("show-tree")
+            )
+            .arg(
+                Arg::new("injections")
+                    .long("injections")
+                    .takes_value(true)
+                    .value_name("LANGUAGE")
+                    .help("also search code embedded in LANGUAGE files")
+                    .long_help("also search code embedded in LANGUAGE files, like JavaScript in HTML <script> tags or code blocks in Markdown. Embedded code is found with LANGUAGE's injections.scm (looked up as LANGUAGE/injections.scm in the query directories), parsed with the embedded language's grammar, and searched with the queries for that language. Positions are reported in the LANGUAGE file. May be given more than once.")
+                    .multiple_occurrences(true)
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+            )
             .arg(
                 Arg::new("no-gitignore")
                     .long("no-gitignore")
@@ -86,16 +313,17 @@ impl Invocation {
                 Arg::new("PATHS")
                     .default_value(".")
                     .help("places to search for matches")
+                    .long_help("places to search for matches. If there's no -q, --pattern, --in, --query-file, or --query-name, the first two of these are the LANGUAGE and QUERY to search for instead.")
                     .multiple_values(true)
             )
             .arg(
                 Arg::new("FORMAT")
                     .long("format")
                     .short('f')
-                    .possible_values(&["lines", "json", "json-lines", "pretty-json", "pretty"])
+                    .possible_values(["lines", "json", "json-lines", "pretty-json", "pretty"])
                     .default_value("pretty")
                     .help("what format should we output lines in?")
-                    .conflicts_with("languages")
+                    .long_help("what format should we output lines in? With --languages, the JSON formats print machine-readable grammar information and the others print a table.")
                     .conflicts_with("show-tree")
             )
             .arg(
@@ -110,6 +338,7 @@ impl Invocation {
                 Arg::new("languages")
                     .long("languages")
                     .help("print the language names tree-grepper knows about")
+                    .long_help("print the grammars tree-grepper can load from its search path, along with the library they came from, their tree-sitter ABI version, how many kinds of nodes they have, and which files they apply to. Use `--format json` to get this in a machine-readable form.")
                     .conflicts_with("additional-query")
                     .conflicts_with("show-tree")
             )
@@ -121,6 +350,41 @@ impl Invocation {
                     .conflicts_with("languages")
                     .conflicts_with("additional-query")
             )
+            .arg(
+                Arg::new("node-types")
+                    .long("node-types")
+                    .help("list the kinds of nodes and fields a language has")
+                    .long_help("list the kinds of nodes and fields LANGUAGE's grammar has, to help write queries. When the grammar's node-types.json is available (it comes with the bundled grammars; for libraries, put it next to the library as LANGUAGE.node-types.json) this also shows supertypes and which fields and children each kind allows. Use `--format json` to get this in a machine-readable form.")
+                    .value_name("LANGUAGE")
+                    .conflicts_with_all(&[
+                        "languages",
+                        "show-tree",
+                        "additional-query",
+                        "pattern",
+                        "in",
+                        "inside",
+                        "not-inside",
+                        "require",
+                        "forbid",
+                        "query-file",
+                        "query-name",
+                        "label",
+                        "capture",
+                        "exclude-capture",
+                        "injections",
+                        "max-count",
+                        "max-results",
+                        "quiet",
+                        "keep-going",
+                    ])
+            )
+            .arg(
+                Arg::new("check-query")
+                    .long("check-query")
+                    .help("check the queries for mistakes instead of searching")
+                    .long_help("check the queries (given the same way as for a search) for mistakes instead of searching: children a kind of node can never have, fields it doesn't have, captures starting with an underscore that no predicate (or --capture) uses, and patterns given more than once. Checking children and fields needs the grammar's node-types.json; see --node-types. Exits with an error if there are any problems.")
+                    .conflicts_with_all(&["languages", "show-tree", "node-types"])
+            )
             .arg(
                 Arg::new("theme")
                     .long("theme")
@@ -173,6 +437,52 @@ impl Invocation {
                     .conflicts_with("languages")
                     .conflicts_with("show-tree")
             )
+            .arg(
+                Arg::new("group")
+                    .long("group")
+                    .short('g')
+                    .help("Output one record per match, with all its captures, instead of one per capture")
+                    .long_help("Output one record per match, with all its captures, instead of one per capture. In JSON, each match has the query that matched, where it starts and ends, and `captures`: an object from capture name to a list of everything captured with that name. In lines output, each match is one line: the location (and query, if there's more than one) followed by each capture as name=\"text\", with the text escaped like a JSON string. With --count, counts matches instead of captures. Doesn't change pretty output.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+            )
+            .arg(
+                Arg::new("max-count")
+                    .long("max-count")
+                    .short('m')
+                    .takes_value(true)
+                    .value_name("N")
+                    .help("Stop searching each file after N matches")
+                    .long_help("Stop searching each file after N matches. Like --count, this counts matches, so a match with several captures only counts once.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+            )
+            .arg(
+                Arg::new("max-results")
+                    .long("max-results")
+                    .takes_value(true)
+                    .value_name("N")
+                    .help("Stop searching after N matches in total")
+                    .long_help("Stop searching after N matches in total, without looking at any more files. Files are searched in parallel, so which matches you get can be different each time.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+            )
+            .arg(
+                Arg::new("quiet")
+                    .long("quiet")
+                    .help("Don't output anything, and stop searching at the first match")
+                    .long_help("Don't output anything, and stop searching at the first match. The exit status says whether anything matched: 0 if it did, and 1 if it didn't.")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+            )
+            .arg(
+                Arg::new("keep-going")
+                    .long("keep-going")
+                    .help("Skip files that can't be searched instead of stopping")
+                    .long_help("Skip files and directories that can't be searched (say, because they can't be read) instead of stopping with an error. The problems are still printed, but the exit status only says whether anything matched. Without this, tree-grepper exits with 0 when something matched, 1 when nothing did, and 2 when there was a problem (including a path to search that doesn't exist.)")
+                    .conflicts_with("languages")
+                    .conflicts_with("show-tree")
+            )
             .arg(
                 Arg::new("only-matching")
                     .long("only-matching")
@@ -197,13 +507,75 @@ impl Invocation {
                     .conflicts_with("languages")
                     .conflicts_with("show-tree")
             )
+            .subcommand(
+                Command::new("grammar")
+                    .about("manage grammar libraries")
+                    .subcommand_required(true)
+                    .arg_required_else_help(true)
+                    .subcommand(
+                        Command::new("build")
+                            .about("compile a grammar from a local checkout so tree-grepper can load it")
+                            .long_about("compile a grammar from a local checkout (containing src/parser.c and maybe src/scanner.c or src/scanner.cc) with the system C compiler, and put the library in the grammar cache ($XDG_CACHE_HOME/tree-grepper/grammars), which is always on the grammar search path. Set $CC or $CXX to use a different compiler.")
+                            .arg(
+                                Arg::new("DIR")
+                                    .required(true)
+                                    .help("the grammar checkout to build")
+                            )
+                            .arg(
+                                Arg::new("name")
+                                    .long("name")
+                                    .takes_value(true)
+                                    .help("what to call the language (defaults to the name in src/grammar.json)")
+                            )
+                            .arg(
+                                Arg::new("out-dir")
+                                    .long("out-dir")
+                                    .takes_value(true)
+                                    .value_name("DIR")
+                                    .help("put the library here instead of the grammar cache")
+                            )
+                    )
+            )
+            .subcommand_negates_reqs(true)
+            .args_conflicts_with_subcommands(true)
             .try_get_matches_from(args)
             .context("could not parse args")?;
 
+        if let Some(("grammar", grammar_matches)) = matches.subcommand() {
+            return Self::grammar_subcommand(grammar_matches);
+        }
+
+        let config = Config::load().context("could not load the config file")?;
+        let aliases = Self::aliases(&config);
+        let grammar_path = config.grammar_search_path(
+            matches
+                .values_of("grammar-dir")
+                .map(|dirs| dirs.map(PathBuf::from).collect())
+                .unwrap_or_default(),
+        );
+        let query_path = config.query_search_path(&grammar_path);
+        let registry = Registry::new(grammar_path, query_path, aliases);
+
         if matches.is_present("languages") {
-            Ok(Self::ShowLanguages)
+            Ok(Self::ShowLanguages(LanguagesOpts {
+                file_types: Self::file_types(&matches, &config, registry.aliases())?,
+                format: Self::format(&matches)?,
+                registry,
+            }))
+        } else if matches.is_present("check-query") {
+            let (positional, _) = Self::positional_query(&matches)?;
+
+            Ok(Self::CheckQueries(CheckQueriesOpts {
+                queries: Self::queries(&matches, positional, &registry)?,
+                shown_captures: Self::capture_names(&matches, "capture"),
+            }))
+        } else if let Some(raw_lang) = matches.value_of("node-types") {
+            Ok(Self::ShowNodeTypes(NodeTypesOpts {
+                language: registry.get(raw_lang).context("could not get language")?,
+                format: Self::format(&matches)?,
+            }))
         } else if let Some(raw_lang) = matches.value_of("show-tree") {
-            let lang = Language::get_language(Path::new("/home/steven/.config/nvim/plugged/nvim-treesitter/parser/"), raw_lang).context("could not get language")?;
+            let lang = registry.get(raw_lang).context("could not get language")?;
 
             let paths = Self::paths(&matches)?;
             if paths.len() != 1 {
@@ -215,35 +587,348 @@ impl Invocation {
                 path: paths[0].to_owned(),
             }))
         } else {
+            let (positional, paths) = Self::positional_query(&matches)?;
+
             Ok(Self::DoQuery(QueryOpts {
-                extractors: Self::extractors(&matches)?,
-                paths: Self::paths(&matches)?,
+                extractors: Self::extractors(&matches, positional, &registry)?,
+                file_types: Self::file_types(&matches, &config, registry.aliases())?,
+                aliases: registry.aliases().clone(),
+                paths,
                 git_ignore: !matches.is_present("no-gitignore"),
-                format: QueryFormat::from_str(
-                    matches.value_of("FORMAT").context("format not provided")?,
-                )
-                .context("could not set format")?,
+                format: Self::format(&matches)?,
                 sort: matches.is_present("sort"),
                 theme: matches.value_of("theme").map(|s| s.to_string()).unwrap(),
-                tab_width: matches.value_of("tab-width").map(|s| s.parse().unwrap()).unwrap_or(4),
+                tab_width: matches
+                    .value_of("tab-width")
+                    .map(|s| s.parse().unwrap())
+                    .unwrap_or(4),
                 no_filename: matches.is_present("no-filename"),
                 no_line_number: matches.is_present("no-line-number"),
                 show_count: matches.is_present("count"),
-                after_lines: matches.value_of("after").or_else(|| matches.value_of("context")).map(|s| s.parse().unwrap()).unwrap_or(0),
-                before_lines: matches.value_of("before").or_else(|| matches.value_of("context")).map(|s| s.parse().unwrap()).unwrap_or(0),
+                show_query: Self::show_query(&matches, positional.is_some()),
+                group: matches.is_present("group"),
+                max_results: if matches.is_present("quiet") {
+                    Some(1)
+                } else {
+                    Self::number(&matches, "max-results")?
+                },
+                quiet: matches.is_present("quiet"),
+                keep_going: matches.is_present("keep-going"),
+                after_lines: matches
+                    .value_of("after")
+                    .or_else(|| matches.value_of("context"))
+                    .map(|s| s.parse().unwrap())
+                    .unwrap_or(0),
+                before_lines: matches
+                    .value_of("before")
+                    .or_else(|| matches.value_of("context"))
+                    .map(|s| s.parse().unwrap())
+                    .unwrap_or(0),
             }))
         }
     }
 
-    fn extractors(matches: &ArgMatches) -> Result<Vec<Extractor>> {
-        let values = match matches.values_of("additional-query") {
-            Some(values) => values,
-            None => bail!("queries were required but not provided. This indicates an internal error and you should report it!"),
+    fn grammar_subcommand(matches: &ArgMatches) -> Result<Self> {
+        match matches.subcommand() {
+            Some(("build", build_matches)) => {
+                let out_dir = match build_matches.value_of("out-dir") {
+                    Some(dir) => PathBuf::from(dir),
+                    None => config::grammar_cache_dir().context(
+                        "could not find the grammar cache directory. Set $HOME or $XDG_CACHE_HOME, or use --out-dir.",
+                    )?,
+                };
+
+                Ok(Self::BuildGrammar(GrammarBuilder::new(
+                    PathBuf::from(build_matches.value_of("DIR").context("DIR was required but not provided. This indicates an internal error and you should report it!")?),
+                    build_matches.value_of("name").map(String::from),
+                    out_dir,
+                )))
+            }
+            _ => bail!("unknown grammar subcommand. This indicates an internal error and you should report it!"),
+        }
+    }
+
+    /// Get the source of every query we were asked to run, whether it was
+    /// given inline, in a file, or by name from the query library, in the
+    /// order they appeared on the command line.
+    fn queries(
+        matches: &ArgMatches,
+        positional: PositionalQuery<'_>,
+        registry: &Registry,
+    ) -> Result<Vec<QuerySource>> {
+        // we need to know where each query was on the command line to put
+        // them in order and to figure out which one each --label is for.
+        let mut positioned: Vec<(usize, QuerySource)> = Vec::new();
+
+        if let Some((raw_lang, raw_query)) = positional {
+            let language = registry.get(raw_lang).with_context(|| {
+                if Path::new(raw_lang).exists() {
+                    format!("`{}` is a path, but without -q the first argument has to be a language (like `tree-grepper rust '(function_item)' src/`.) If you meant to search {} with another query, use -q LANGUAGE QUERY.", raw_lang, raw_lang)
+                } else {
+                    format!("I expected `{}` to be a language, since without -q the first argument is the language and the second is the query (like `tree-grepper rust '(function_item)' src/`.) Run `tree-grepper --languages` to see the languages I know about.", raw_lang)
+                }
+            })?;
+
+            positioned.push((
+                matches
+                    .index_of("PATHS")
+                    .context("PATHS had a value but no index. This indicates an internal error and you should report it!")?,
+                QuerySource {
+                    language,
+                    source: raw_query.to_string(),
+                    label: None,
+                    regex: None,
+                },
+            ));
+        }
+
+        for ((raw_lang, raw_query), index) in matches
+            .values_of("additional-query")
+            .into_iter()
+            .flatten()
+            .tuples()
+            .zip(Self::occurrence_indices(matches, "additional-query", 2))
+        {
+            positioned.push((
+                index,
+                QuerySource {
+                    language: registry.get(raw_lang).context("could not get language")?,
+                    source: raw_query.to_string(),
+                    // numbered below, once we know the order
+                    label: None,
+                    regex: None,
+                },
+            ));
+        }
+
+        for ((raw_lang, raw_pattern), index) in matches
+            .values_of("pattern")
+            .into_iter()
+            .flatten()
+            .tuples()
+            .zip(Self::occurrence_indices(matches, "pattern", 2))
+        {
+            let language = registry.get(raw_lang).context("could not get language")?;
+            positioned.push((
+                index,
+                QuerySource {
+                    source: pattern::to_query(&language, raw_pattern)
+                        .with_context(|| format!("could not use the pattern `{}`", raw_pattern))?,
+                    language,
+                    // numbered below, like -q
+                    label: None,
+                    regex: None,
+                },
+            ));
+        }
+
+        for ((raw_lang, raw_scope, raw_regex), index) in matches
+            .values_of("in")
+            .into_iter()
+            .flatten()
+            .tuples()
+            .zip(Self::occurrence_indices(matches, "in", 3))
+        {
+            let language = registry.get(raw_lang).context("could not get language")?;
+            positioned.push((
+                index,
+                QuerySource {
+                    source: scope::to_query(&language, raw_scope)
+                        .with_context(|| format!("could not search in `{}`", raw_scope))?,
+                    language,
+                    // numbered below, like -q
+                    label: None,
+                    regex: Some(
+                        Regex::new(raw_regex).with_context(|| {
+                            format!("could not parse the regex `{}`", raw_regex)
+                        })?,
+                    ),
+                },
+            ));
+        }
+
+        for ((raw_lang, raw_path), index) in matches
+            .values_of("query-file")
+            .into_iter()
+            .flatten()
+            .tuples()
+            .zip(Self::occurrence_indices(matches, "query-file", 2))
+        {
+            let path = PathBuf::from(raw_path);
+            positioned.push((
+                index,
+                QuerySource {
+                    language: registry.get(raw_lang).context("could not get language")?,
+                    source: fs::read_to_string(&path)
+                        .with_context(|| format!("could not read the query in {}", raw_path))?,
+                    label: path
+                        .file_stem()
+                        .and_then(|stem| stem.to_str())
+                        .map(String::from),
+                    regex: None,
+                },
+            ));
+        }
+
+        for ((raw_lang, name), index) in matches
+            .values_of("query-name")
+            .into_iter()
+            .flatten()
+            .tuples()
+            .zip(Self::occurrence_indices(matches, "query-name", 2))
+        {
+            let lang = registry.get(raw_lang).context("could not get language")?;
+            let path = match registry.find_query(&lang, name) {
+                Some(path) => path,
+                None => {
+                    let available = registry.query_names(&lang);
+                    bail!(
+                        "I couldn't find a query named {} for {}. I looked for {}/{}.scm in these directories:\n{}{}",
+                        name,
+                        lang.name(),
+                        lang.name(),
+                        name,
+                        registry
+                            .query_path()
+                            .iter()
+                            .map(|dir| format!("  - {}", dir.display()))
+                            .join("\n"),
+                        if available.is_empty() {
+                            String::new()
+                        } else {
+                            format!(
+                                "\nThese {} queries are available: {}",
+                                lang.name(),
+                                available.iter().join(", ")
+                            )
+                        }
+                    )
+                }
+            };
+
+            positioned.push((
+                index,
+                QuerySource {
+                    language: lang,
+                    source: fs::read_to_string(&path).with_context(|| {
+                        format!("could not read the query in {}", path.display())
+                    })?,
+                    label: Some(name.to_string()),
+                    regex: None,
+                },
+            ));
+        }
+
+        if positioned.is_empty() {
+            bail!("queries were required but not provided. This indicates an internal error and you should report it!")
+        }
+
+        positioned.sort_by_key(|(index, _)| *index);
+
+        // a label goes with the query right before it
+        let mut labelled = vec![false; positioned.len()];
+        for (label, label_index) in matches
+            .values_of("label")
+            .into_iter()
+            .flatten()
+            .zip(matches.indices_of("label").into_iter().flatten())
+        {
+            let position = match positioned
+                .iter()
+                .rposition(|(index, _)| *index < label_index)
+            {
+                Some(position) => position,
+                None => bail!("--label {} needs to come after the query it labels", label),
+            };
+
+            if labelled[position] {
+                bail!(
+                    "--label {} is the second label for the same query. Each query can only have one.",
+                    label
+                )
+            }
+            labelled[position] = true;
+            positioned[position].1.label = Some(label.to_string());
+        }
+
+        Ok(positioned
+            .into_iter()
+            .enumerate()
+            .map(|(number, (_, mut query))| {
+                query.label.get_or_insert_with(|| (number + 1).to_string());
+                query
+            })
+            .collect())
+    }
+
+    /// Where each occurrence of an argument that takes several values (like
+    /// `-q LANGUAGE QUERY`) starts on the command line.
+    fn occurrence_indices(matches: &ArgMatches, name: &str, values: usize) -> Vec<usize> {
+        matches
+            .indices_of(name)
+            .into_iter()
+            .flatten()
+            .step_by(values)
+            .collect()
+    }
+
+    /// We only add the query to each line of output when it could be
+    /// ambiguous, or when someone asked for it by labelling a query.
+    fn show_query(matches: &ArgMatches, has_positional: bool) -> bool {
+        let queries = matches.occurrences_of("additional-query")
+            + matches.occurrences_of("pattern")
+            + matches.occurrences_of("in")
+            + matches.occurrences_of("query-file")
+            + matches.occurrences_of("query-name")
+            + u64::from(has_positional);
+
+        queries > 1 || matches.is_present("label")
+    }
+
+    /// Split the positional arguments into a LANGUAGE and QUERY (if they're
+    /// there) and the paths to search. See the comment at the top of
+    /// `from_args` for the rules.
+    fn positional_query(matches: &ArgMatches) -> Result<(PositionalQuery<'_>, Vec<PathBuf>)> {
+        let has_flag_query = matches.is_present("additional-query")
+            || matches.is_present("pattern")
+            || matches.is_present("in")
+            || matches.is_present("query-file")
+            || matches.is_present("query-name");
+        if has_flag_query {
+            return Ok((None, Self::paths(matches)?));
+        }
+
+        // PATHS defaults to `.`, which isn't a language!
+        let mut positionals: Vec<&str> = if matches.occurrences_of("PATHS") == 0 {
+            Vec::new()
+        } else {
+            matches.values_of("PATHS").into_iter().flatten().collect()
+        };
+
+        if positionals.len() < 2 {
+            bail!(
+                "I need a language and a query to search for, like `tree-grepper rust '(function_item)' src/`, or `-q LANGUAGE QUERY` (which you can give more than once.) Run `tree-grepper --help` for more."
+            )
+        }
+
+        let rest = positionals.split_off(2);
+        let paths = if rest.is_empty() {
+            vec![PathBuf::from(".")]
+        } else {
+            rest.into_iter().map(PathBuf::from).collect()
         };
 
+        Ok((Some((positionals[0], positionals[1])), paths))
+    }
+
+    fn extractors(
+        matches: &ArgMatches,
+        positional: PositionalQuery<'_>,
+        registry: &Registry,
+    ) -> Result<Vec<Extractor>> {
         // the most common case is going to be one query, so let's allocate
         // that immediately...
-        let mut query_strings: HashMap<String, (Language, String)> = HashMap::with_capacity(1);
+        let mut query_strings: HashMap<String, CombinedQuery> = HashMap::with_capacity(1);
 
         // If you have two tree-sitter queries `(one)` and `(two)`, you can
         // join them together in a single string like `(one)(two)`. In that
@@ -254,37 +939,266 @@ impl Invocation {
         // can't specify queries across multiple languages! Nobody should ever
         // notice, except that they won't see as much of a slowdown for adding
         // new queries to an invocation as they might expect. (Well, hopefully!)
-        for (raw_lang, raw_query) in values.tuples() {
-            let lang = Language::get_language(Path::new("/home/steven/.config/nvim/plugged/nvim-treesitter/parser/"), raw_lang).context("could not get language")?;
-
-            let mut query_out = String::from(raw_query);
+        //
+        // Queries from files can end in a comment, so we put each query on
+        // its own line to keep it from swallowing whatever comes next.
+        //
+        // Patterns keep their order when we combine them, so we can tell
+        // which query a match came from by its pattern index. We keep track
+        // of the label (and `--in` regex) for each pattern as we go.
+        for QuerySource {
+            language: lang,
+            source,
+            label,
+            regex,
+        } in Self::queries(matches, positional, registry)?
+        {
+            let mut query_out = source;
 
-            let temp_query = tree_sitter::Query::new(lang.ts_lang(), raw_query).context("could not parse query")?;
+            let temp_query = lang.query(&query_out).context("could not parse query")?;
 
             if temp_query.capture_names().is_empty() {
-                query_out.push_str("@query");
+                query_out.push_str("\n@query");
             }
 
-            if let Some((_lang, existing)) = query_strings.get_mut(raw_lang) {
+            let label = label.unwrap_or_default();
+            let pattern_labels = std::iter::repeat_n(label, temp_query.pattern_count());
+            let pattern_regexes = std::iter::repeat_n(regex, temp_query.pattern_count());
+
+            // we key on the grammar's name rather than what was typed so
+            // that aliases (like `js` and `javascript`) get combined too.
+            if let Some((_lang, existing, labels, regexes)) = query_strings.get_mut(lang.name()) {
+                existing.push('\n');
                 existing.push_str(&query_out);
+                labels.extend(pattern_labels);
+                regexes.extend(pattern_regexes);
             } else {
-                query_strings.insert(raw_lang.to_string(), (lang, query_out));
+                query_strings.insert(
+                    lang.name().to_string(),
+                    (
+                        lang,
+                        query_out,
+                        pattern_labels.collect(),
+                        pattern_regexes.collect(),
+                    ),
+                );
             }
         }
 
         let mut out = Vec::with_capacity(query_strings.len());
-        for (_lang_str, (lang, raw_query)) in query_strings {
-            let query = tree_sitter::Query::new(lang.ts_lang(), &raw_query).context("could not parse combined query")?;
-            for i in 0..query.pattern_count() {
-                let preds = query.general_predicates(i);
-                if !preds.is_empty() {
-                    return Err(anyhow::anyhow!("Unknown predicate '{}'", preds[0].operator));
+        for (_lang_str, (lang, raw_query, labels, regexes)) in query_strings {
+            let query = lang
+                .query(&raw_query)
+                .context("could not parse combined query")?;
+            out.push(
+                Extractor::new(lang, query, labels)
+                    .context("could not use the query")?
+                    .with_regexes(regexes),
+            )
+        }
+
+        for raw_host in matches.values_of("injections").into_iter().flatten() {
+            let host = registry.get(raw_host).context("could not get language")?;
+
+            let source = registry
+                .injections_source(&host)?
+                .with_context(|| format!("I couldn't find an injections.scm for {}. I looked for {}/injections.scm in these directories:\n{}", host.name(), host.name(), registry.query_path().iter().map(|dir| format!("  - {}", dir.display())).join("\n")))?;
+            let injections = host.query(&source).with_context(|| {
+                format!("could not parse the injections query for {}", host.name())
+            })?;
+
+            // if we're already searching the host language, we look for
+            // injections while we're at it. Otherwise we add an extractor
+            // that doesn't match anything itself, just so that we look in
+            // the host language's files at all.
+            let extractor = match out
+                .iter()
+                .position(|extractor| extractor.language().name() == host.name())
+            {
+                Some(index) => out.swap_remove(index),
+                None => {
+                    let empty = host.query("").context("could not make an empty query")?;
+                    Extractor::new(host.clone(), empty, Vec::new())?
+                }
+            };
+
+            out.push(extractor.with_injections(injections).with_context(|| {
+                format!("could not use the injections query for {}", host.name())
+            })?);
+        }
+
+        // scopes are checked against the tree of the file being searched,
+        // so they go with the extractor for their language.
+        for (arg, inside) in [("inside", true), ("not-inside", false)] {
+            for (raw_lang, raw_query) in matches.values_of(arg).into_iter().flatten().tuples() {
+                let lang = registry.get(raw_lang).context("could not get language")?;
+
+                let mut source = raw_query.to_string();
+                if lang
+                    .query(&source)
+                    .with_context(|| format!("could not parse the --{} query", arg))?
+                    .capture_names()
+                    .is_empty()
+                {
+                    source.push_str("\n@scope");
                 }
+                let query = lang
+                    .query(&source)
+                    .with_context(|| format!("could not parse the --{} query", arg))?;
+
+                let extractor = Self::take_extractor(&mut out, &lang, raw_lang, arg)?;
+                out.push(
+                    extractor
+                        .with_scope(query, inside)
+                        .with_context(|| format!("could not use the --{} query", arg))?,
+                );
+            }
+        }
+
+        // like scopes, file conditions are checked against the tree of the
+        // file being searched.
+        for (arg, required) in [("require", true), ("forbid", false)] {
+            for (raw_lang, raw_query) in matches.values_of(arg).into_iter().flatten().tuples() {
+                let lang = registry.get(raw_lang).context("could not get language")?;
+                let query = lang
+                    .query(raw_query)
+                    .with_context(|| format!("could not parse the --{} query", arg))?;
+
+                let extractor = Self::take_extractor(&mut out, &lang, raw_lang, arg)?;
+                out.push(
+                    extractor
+                        .with_file_condition(query, required)
+                        .with_context(|| format!("could not use the --{} query", arg))?,
+                );
+            }
+        }
+
+        let filter = Self::capture_filter(matches, &out)?;
+
+        // with --quiet, one match anywhere is all we need
+        let max_matches = if matches.is_present("quiet") {
+            Some(1)
+        } else {
+            Self::number(matches, "max-count")?
+        };
+
+        Ok(out
+            .into_iter()
+            .map(|extractor| {
+                extractor
+                    .with_capture_filter(&filter)
+                    .with_max_matches(max_matches)
+            })
+            .collect())
+    }
+
+    /// Take the extractor for `lang` out of `extractors`, so we can add to it
+    /// for `--ARG` and put it back.
+    fn take_extractor(
+        extractors: &mut Vec<Extractor>,
+        lang: &Language,
+        raw_lang: &str,
+        arg: &str,
+    ) -> Result<Extractor> {
+        let index = extractors
+            .iter()
+            .position(|extractor| extractor.language().name() == lang.name())
+            .with_context(|| {
+                format!(
+                    "--{} {} only applies to {} files, but none of the queries search {}. Give a query for {} too.",
+                    arg,
+                    raw_lang,
+                    lang.name(),
+                    lang.name(),
+                    lang.name()
+                )
+            })?;
+
+        Ok(extractors.swap_remove(index))
+    }
+
+    /// Get the captures to output from --capture and --exclude-capture,
+    /// making sure that every name they mention is in some query.
+    fn capture_filter(matches: &ArgMatches, extractors: &[Extractor]) -> Result<CaptureFilter> {
+        let filter = CaptureFilter::new(
+            Self::capture_names(matches, "capture"),
+            Self::capture_names(matches, "exclude-capture"),
+        );
+
+        let available: Vec<&str> = extractors
+            .iter()
+            .flat_map(|extractor| extractor.capture_names())
+            .map(String::as_str)
+            .unique()
+            .collect();
+
+        for name in filter.names() {
+            if !available.contains(&name) {
+                bail!(
+                    "none of the queries capture anything called @{}. The captures are: {}",
+                    name,
+                    available.iter().map(|name| format!("@{}", name)).join(", ")
+                )
             }
-            out.push(Extractor::new(lang, query))
         }
 
-        Ok(out)
+        Ok(filter)
+    }
+
+    /// Get the capture names given to `--capture` or `--exclude-capture`.
+    fn capture_names(matches: &ArgMatches, arg: &str) -> Vec<String> {
+        matches
+            .values_of(arg)
+            .into_iter()
+            .flatten()
+            // people might write `--capture @name`, like in the query
+            .map(|name| name.trim_start_matches('@').to_string())
+            .collect()
+    }
+
+    /// Get a number (like --max-count) if it was given.
+    fn number(matches: &ArgMatches, name: &str) -> Result<Option<usize>> {
+        matches
+            .value_of(name)
+            .map(|raw| {
+                raw.parse()
+                    .with_context(|| format!("--{} needs a whole number, but got `{}`", name, raw))
+            })
+            .transpose()
+    }
+
+    fn aliases(config: &Config) -> Aliases {
+        let mut aliases = Aliases::new();
+
+        for (alias, language) in &config.aliases {
+            aliases.add(alias, language);
+        }
+
+        aliases
+    }
+
+    fn file_types(matches: &ArgMatches, config: &Config, aliases: &Aliases) -> Result<FileTypes> {
+        let mut file_types = FileTypes::new();
+
+        for (language, globs) in &config.file_types {
+            let language = aliases.resolve(language);
+            for glob in globs {
+                file_types.add(&language, glob);
+            }
+        }
+
+        for def in matches.values_of("type-add").into_iter().flatten() {
+            let (language, glob) =
+                FileTypes::parse_def(def).context("could not add a file type from --type-add")?;
+            file_types.add(&aliases.resolve(language), glob);
+        }
+
+        Ok(file_types)
+    }
+
+    fn format(matches: &ArgMatches) -> Result<QueryFormat> {
+        QueryFormat::from_str(matches.value_of("FORMAT").context("format not provided")?)
+            .context("could not set format")
     }
 
     fn paths(matches: &ArgMatches) -> Result<Vec<PathBuf>> {
@@ -322,3 +1236,35 @@ impl FromStr for QueryFormat {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_types_come_from_config_and_flags() {
+        let config: Config = toml::from_str(
+            r#"
+            [file-types]
+            rs = ["*.rs.in"]
+            klingon = ["*.tlh"]
+            "#,
+        )
+        .unwrap();
+        let matches = Command::new("tree-grepper")
+            .arg(
+                Arg::new("type-add")
+                    .long("type-add")
+                    .takes_value(true)
+                    .multiple_occurrences(true),
+            )
+            .get_matches_from(["tree-grepper", "--type-add", "klingon:*.klg"]);
+
+        let file_types = Invocation::file_types(&matches, &config, &Aliases::new()).unwrap();
+
+        assert!(file_types
+            .globs_for("rust")
+            .contains(&"*.rs.in".to_string()));
+        assert_eq!(file_types.globs_for("klingon"), vec!["*.tlh", "*.klg"]);
+    }
+}
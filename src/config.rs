@@ -0,0 +1,385 @@
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// An environment variable holding extra grammar directories, separated the
+/// same way as `PATH`.
+pub const GRAMMAR_PATH_VAR: &str = "TREE_GREPPER_GRAMMAR_PATH";
+
+/// Settings read from `$XDG_CONFIG_HOME/tree-grepper/config.toml`. Every
+/// field is optional, and a missing file is the same as an empty one.
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Config {
+    /// Directories to look for grammar libraries in, searched after the ones
+    /// given on the command line or in the environment.
+    #[serde(default)]
+    pub grammar_dirs: Vec<PathBuf>,
+
+    /// Extra globs for each language, like `lua = ["*.rockspec"]`. These are
+    /// added to the built-in ones, not used instead of them.
+    #[serde(default)]
+    pub file_types: HashMap<String, Vec<String>>,
+
+    /// Other names for languages, like `pl = "perl"`.
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+
+    /// Directories laid out like `LANGUAGE/NAME.scm` to look for queries in,
+    /// such as `injections.scm`.
+    #[serde(default)]
+    pub query_dirs: Vec<PathBuf>,
+}
+
+impl Config {
+    pub fn load() -> Result<Config> {
+        match config_home() {
+            Some(home) => Self::load_from(&home.join("tree-grepper").join("config.toml")),
+            None => Ok(Config::default()),
+        }
+    }
+
+    pub fn load_from(path: &Path) -> Result<Config> {
+        let raw = match fs::read_to_string(path) {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(err) => {
+                return Err(err).with_context(|| format!("could not read {}", path.display()))
+            }
+        };
+
+        toml::from_str(&raw).with_context(|| format!("could not parse {}", path.display()))
+    }
+
+    /// Build the ordered list of directories to look for grammars in. The
+    /// first directory containing a matching library wins, so the order here
+    /// is the order of precedence: flags, then the environment, then the
+    /// config file, then grammars built with `tree-grepper grammar build`,
+    /// then the XDG data directories.
+    pub fn grammar_search_path(&self, from_flags: Vec<PathBuf>) -> Vec<PathBuf> {
+        let mut out = from_flags;
+
+        if let Some(raw) = env::var_os(GRAMMAR_PATH_VAR) {
+            out.extend(env::split_paths(&raw).filter(|p| !p.as_os_str().is_empty()));
+        }
+
+        out.extend(self.grammar_dirs.iter().map(|dir| expand_tilde(dir)));
+
+        if let Some(cache_dir) = grammar_cache_dir() {
+            out.push(cache_dir);
+        }
+
+        if let Some(data_home) = data_home() {
+            out.push(data_home.join("tree-grepper").join("grammars"));
+        }
+
+        out.extend(
+            data_dirs()
+                .into_iter()
+                .map(|dir| dir.join("tree-grepper").join("grammars")),
+        );
+
+        out.into_iter().unique().collect()
+    }
+
+    /// Build the ordered list of directories to look for query files in. Each
+    /// directory has a subdirectory per language, like nvim-treesitter's
+    /// `queries` directory. We look in the ones from the config file first,
+    /// then the XDG config and data directories, and then next to each grammar
+    /// directory (so a `parser` directory from nvim-treesitter picks up the
+    /// `queries` directory beside it.)
+    pub fn query_search_path(&self, grammar_path: &[PathBuf]) -> Vec<PathBuf> {
+        let mut out: Vec<PathBuf> = self
+            .query_dirs
+            .iter()
+            .map(|dir| expand_tilde(dir))
+            .collect();
+
+        if let Some(config_home) = config_home() {
+            out.push(config_home.join("tree-grepper").join("queries"));
+        }
+
+        if let Some(data_home) = data_home() {
+            out.push(data_home.join("tree-grepper").join("queries"));
+        }
+
+        out.extend(
+            data_dirs()
+                .into_iter()
+                .map(|dir| dir.join("tree-grepper").join("queries")),
+        );
+
+        out.extend(
+            grammar_path
+                .iter()
+                .filter_map(|dir| dir.parent())
+                .map(|parent| parent.join("queries")),
+        );
+
+        out.into_iter().unique().collect()
+    }
+}
+
+fn home() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .filter(|home| !home.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Look up an XDG base directory, ignoring it if it's empty or relative (as
+/// the spec asks us to) and falling back to a path under `$HOME`.
+fn xdg_dir(var: &str, fallback: &str) -> Option<PathBuf> {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| home().map(|home| home.join(fallback)))
+}
+
+pub fn config_home() -> Option<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+pub fn data_home() -> Option<PathBuf> {
+    xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+pub fn cache_home() -> Option<PathBuf> {
+    xdg_dir("XDG_CACHE_HOME", ".cache")
+}
+
+/// Where `tree-grepper grammar build` puts the libraries it compiles.
+pub fn grammar_cache_dir() -> Option<PathBuf> {
+    cache_home().map(|cache| cache.join("tree-grepper").join("grammars"))
+}
+
+fn data_dirs() -> Vec<PathBuf> {
+    match env::var_os("XDG_DATA_DIRS").filter(|dirs| !dirs.is_empty()) {
+        Some(dirs) => env::split_paths(&dirs)
+            .filter(|dir| dir.is_absolute())
+            .collect(),
+        None => vec![
+            PathBuf::from("/usr/local/share"),
+            PathBuf::from("/usr/share"),
+        ],
+    }
+}
+
+fn expand_tilde(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), home()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsString;
+    use std::sync::Mutex;
+
+    /// The environment is shared by every test in the process, so only one
+    /// test at a time gets to change it.
+    static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+    const VARS: [&str; 6] = [
+        "HOME",
+        "XDG_CONFIG_HOME",
+        "XDG_DATA_HOME",
+        "XDG_CACHE_HOME",
+        "XDG_DATA_DIRS",
+        GRAMMAR_PATH_VAR,
+    ];
+
+    /// Run `f` with the variables we read set to `vars` (and the rest unset),
+    /// putting the old values back afterwards.
+    fn with_env<T>(vars: &[(&str, &str)], f: impl FnOnce() -> T) -> T {
+        let _guard = ENV_LOCK.lock().unwrap_or_else(|err| err.into_inner());
+        let saved: Vec<(&str, Option<OsString>)> =
+            VARS.iter().map(|var| (*var, env::var_os(var))).collect();
+
+        for var in VARS {
+            env::remove_var(var);
+        }
+        for (var, value) in vars {
+            env::set_var(var, value);
+        }
+
+        let out = f();
+
+        for (var, value) in saved {
+            match value {
+                Some(value) => env::set_var(var, value),
+                None => env::remove_var(var),
+            }
+        }
+
+        out
+    }
+
+    fn paths(raw: &[&str]) -> Vec<PathBuf> {
+        raw.iter().map(PathBuf::from).collect()
+    }
+
+    #[test]
+    fn grammar_search_path_precedence() {
+        let config = Config {
+            grammar_dirs: paths(&["/config/grammars"]),
+            ..Config::default()
+        };
+
+        let found = with_env(
+            &[
+                ("HOME", "/home/me"),
+                ("XDG_CACHE_HOME", "/cache"),
+                ("XDG_DATA_HOME", "/data"),
+                ("XDG_DATA_DIRS", "/share"),
+                (GRAMMAR_PATH_VAR, "/env"),
+            ],
+            || config.grammar_search_path(paths(&["/flag"])),
+        );
+
+        assert_eq!(
+            found,
+            paths(&[
+                "/flag",
+                "/env",
+                "/config/grammars",
+                "/cache/tree-grepper/grammars",
+                "/data/tree-grepper/grammars",
+                "/share/tree-grepper/grammars",
+            ])
+        );
+    }
+
+    #[test]
+    fn grammar_search_path_defaults() {
+        let found = with_env(&[("HOME", "/home/me")], || {
+            Config::default().grammar_search_path(Vec::new())
+        });
+
+        assert_eq!(
+            found,
+            paths(&[
+                "/home/me/.cache/tree-grepper/grammars",
+                "/home/me/.local/share/tree-grepper/grammars",
+                "/usr/local/share/tree-grepper/grammars",
+                "/usr/share/tree-grepper/grammars",
+            ])
+        );
+    }
+
+    #[test]
+    fn grammar_search_path_drops_duplicates() {
+        let config = Config {
+            grammar_dirs: paths(&["/flag"]),
+            ..Config::default()
+        };
+
+        let found = with_env(&[("HOME", "/home/me")], || {
+            config.grammar_search_path(paths(&["/flag"]))
+        });
+
+        assert_eq!(
+            found
+                .iter()
+                .filter(|dir| *dir == Path::new("/flag"))
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn grammar_path_var_splits_like_path() {
+        let found = with_env(&[(GRAMMAR_PATH_VAR, "/one::/two:")], || {
+            Config::default().grammar_search_path(Vec::new())
+        });
+
+        assert_eq!(found[..2], paths(&["/one", "/two"]));
+    }
+
+    #[test]
+    fn config_grammar_dirs_expand_tilde() {
+        let config = Config {
+            grammar_dirs: paths(&["~/grammars"]),
+            ..Config::default()
+        };
+
+        let found = with_env(&[("HOME", "/home/me")], || {
+            config.grammar_search_path(Vec::new())
+        });
+
+        assert_eq!(found[0], PathBuf::from("/home/me/grammars"));
+    }
+
+    #[test]
+    fn expand_tilde_uses_home() {
+        with_env(&[("HOME", "/home/me")], || {
+            assert_eq!(
+                expand_tilde(Path::new("~/grammars")),
+                PathBuf::from("/home/me/grammars")
+            );
+            assert_eq!(expand_tilde(Path::new("~")), PathBuf::from("/home/me"));
+            assert_eq!(
+                expand_tilde(Path::new("/opt/grammars")),
+                PathBuf::from("/opt/grammars")
+            );
+            // `~user` means someone else's home, which we don't try to find
+            assert_eq!(
+                expand_tilde(Path::new("~other/grammars")),
+                PathBuf::from("~other/grammars")
+            );
+        })
+    }
+
+    #[test]
+    fn expand_tilde_without_home() {
+        with_env(&[("HOME", "")], || {
+            assert_eq!(
+                expand_tilde(Path::new("~/grammars")),
+                PathBuf::from("~/grammars")
+            );
+        })
+    }
+
+    #[test]
+    fn xdg_dir_uses_absolute_values() {
+        with_env(&[("HOME", "/home/me"), ("XDG_DATA_HOME", "/data")], || {
+            assert_eq!(
+                xdg_dir("XDG_DATA_HOME", ".local/share"),
+                Some(PathBuf::from("/data"))
+            );
+        })
+    }
+
+    #[test]
+    fn xdg_dir_ignores_relative_values() {
+        with_env(
+            &[("HOME", "/home/me"), ("XDG_DATA_HOME", "relative/data")],
+            || {
+                assert_eq!(
+                    xdg_dir("XDG_DATA_HOME", ".local/share"),
+                    Some(PathBuf::from("/home/me/.local/share"))
+                );
+            },
+        )
+    }
+
+    #[test]
+    fn xdg_dir_without_home() {
+        with_env(&[], || {
+            assert_eq!(xdg_dir("XDG_DATA_HOME", ".local/share"), None);
+        })
+    }
+
+    #[test]
+    fn data_dirs_ignores_relative_entries() {
+        with_env(&[("XDG_DATA_DIRS", "relative:/share")], || {
+            assert_eq!(data_dirs(), paths(&["/share"]));
+        })
+    }
+}
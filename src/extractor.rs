@@ -1,112 +1,991 @@
 use crate::language::Language;
-use anyhow::{Context, Result};
-use serde::ser::{SerializeStruct, Serializer};
+use anyhow::{bail, Context, Result};
+use itertools::Itertools;
+use regex::Regex;
+use serde::ser::{SerializeMap, SerializeStruct, Serializer};
 use serde::Serialize;
+use std::collections::{HashMap, HashSet};
 use std::fmt::{self, Display};
 use std::fs;
 use std::path::{Path, PathBuf};
-use tree_sitter::{Parser, Point, Query, QueryCursor};
+use tree_sitter::{
+    Node, Parser, Point, Query, QueryCursor, QueryMatch, QueryPredicate, QueryPredicateArg, Range,
+    Tree,
+};
+
+/// How many levels of injections-inside-injections we'll follow. Grammars
+/// like Rust inject themselves (into macro bodies), so without a limit we
+/// could go on for quite a while.
+const MAX_INJECTION_DEPTH: usize = 4;
 
 #[derive(Debug)]
 pub struct Extractor {
     language: Language,
     ts_language: tree_sitter::Language,
     query: Query,
+    predicates: Predicates,
     captures: Vec<String>,
+    /// Whether to output each capture, by capture index.
+    shown: Vec<bool>,
+    /// The label of the query each pattern came from, by pattern index.
+    pattern_labels: Vec<String>,
+    /// For patterns from `--in`, the regex to look for inside each capture,
+    /// by pattern index.
+    regexes: Vec<Option<Regex>>,
+    injections: Option<Injections>,
+    scopes: Vec<Scope>,
+    file_conditions: Vec<FileCondition>,
+    /// The most matches to report in each file, from `--max-count`.
+    max_matches: Option<usize>,
 }
 
-impl Extractor {
-    pub fn new(language: Language, mut query: Query) -> Extractor {
-        let captures = query.capture_names().to_vec();
+/// Which captures to output. By default, that's every capture whose name
+/// doesn't start with an underscore.
+#[derive(Debug, Default)]
+pub struct CaptureFilter {
+    /// If this isn't empty, only these captures are output (even if they
+    /// start with an underscore.)
+    only: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl CaptureFilter {
+    pub fn new(only: Vec<String>, exclude: Vec<String>) -> CaptureFilter {
+        CaptureFilter { only, exclude }
+    }
+
+    /// Every capture name the filter mentions.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.only
+            .iter()
+            .chain(self.exclude.iter())
+            .map(String::as_str)
+    }
+
+    fn shows(&self, name: &str) -> bool {
+        if self.exclude.iter().any(|excluded| excluded == name) {
+            return false;
+        }
+
+        if self.only.is_empty() {
+            !name.starts_with('_')
+        } else {
+            self.only.iter().any(|only| only == name)
+        }
+    }
+}
+
+/// A query from `--inside` or `--not-inside`. Its captures (except ones
+/// starting with an underscore, which are only for predicates) mark parts
+/// of a file, and we only keep matches that are inside (or not inside) one
+/// of them.
+#[derive(Debug)]
+struct Scope {
+    query: Query,
+    predicates: Predicates,
+    /// Whether matches have to be inside the scope, rather than outside it.
+    inside: bool,
+}
+
+impl Scope {
+    fn new(query: Query, inside: bool) -> Result<Scope> {
+        if query
+            .capture_names()
+            .iter()
+            .all(|name| name.starts_with('_'))
+        {
+            bail!("the query has to capture something to search inside of, and captures starting with an underscore don't count")
+        }
+
+        Ok(Scope {
+            predicates: Predicates::new(&query, false)?,
+            query,
+            inside,
+        })
+    }
+
+    /// Where everything the query captures starts and ends.
+    fn ranges(&self, root: Node, source: &[u8]) -> Vec<(Point, Point)> {
+        let capture_names = self.query.capture_names();
+
+        let mut cursor = QueryCursor::new();
+        cursor
+            .matches(&self.query, root, source)
+            .filter(|query_match| self.predicates.satisfied(query_match, source))
+            .flat_map(|query_match| query_match.captures)
+            .filter(|capture| !capture_names[capture.index as usize].starts_with('_'))
+            .map(|capture| (capture.node.start_position(), capture.node.end_position()))
+            .collect()
+    }
+}
+
+/// A query from `--require` or `--forbid`. Files are only searched when it
+/// matches somewhere in them (or, for `--forbid`, when it doesn't.)
+#[derive(Debug)]
+struct FileCondition {
+    query: Query,
+    predicates: Predicates,
+    /// Whether the query has to match, rather than not match.
+    required: bool,
+}
+
+impl FileCondition {
+    fn new(query: Query, required: bool) -> Result<FileCondition> {
+        Ok(FileCondition {
+            predicates: Predicates::new(&query, false)?,
+            query,
+            required,
+        })
+    }
+
+    fn holds(&self, root: Node, source: &[u8]) -> bool {
+        let mut cursor = QueryCursor::new();
+        let found = cursor
+            .matches(&self.query, root, source)
+            .any(|query_match| self.predicates.satisfied(&query_match, source));
 
-        captures.iter().for_each(|name| {
-            if name.starts_with('_') {
-                query.disable_capture(name);
+        found == self.required
+    }
+}
+
+/// An `injections.scm`-style query saying which parts of a file are written
+/// in some other language. We understand both the current capture names
+/// (`@injection.content`, `@injection.language`) and the older ones
+/// nvim-treesitter uses (`@content`, `@language`), plus the
+/// `injection.language`, `injection.combined`, and
+/// `injection.include-children` properties set with `#set!`.
+#[derive(Debug)]
+struct Injections {
+    query: Query,
+    predicates: Predicates,
+    content_capture: Option<u32>,
+    language_capture: Option<u32>,
+}
+
+impl Injections {
+    fn new(query: Query) -> Result<Injections> {
+        Ok(Injections {
+            // injections.scm files written for editors often use directives
+            // like `#offset!` that we don't have any use for.
+            predicates: Predicates::new(&query, true)?,
+            content_capture: query
+                .capture_index_for_name("injection.content")
+                .or_else(|| query.capture_index_for_name("content")),
+            language_capture: query
+                .capture_index_for_name("injection.language")
+                .or_else(|| query.capture_index_for_name("language")),
+            query,
+        })
+    }
+
+    /// Find the ranges of `source` to parse as each injected language. The
+    /// language names are whatever the query says, so they may be aliases.
+    fn ranges(&self, root: Node, source: &[u8]) -> Vec<(String, Vec<Range>)> {
+        let content_capture = match self.content_capture {
+            Some(index) => index,
+            None => return Vec::new(),
+        };
+
+        let mut out: Vec<(String, Vec<Range>)> = Vec::new();
+
+        // combined injections are parsed as a single document per language
+        // (think of all the PHP snippets in an HTML file) so we collect them
+        // here and add them at the end.
+        let mut combined: HashMap<(usize, String), Vec<Range>> = HashMap::new();
+
+        let mut cursor = QueryCursor::new();
+        for query_match in cursor
+            .matches(&self.query, root, source)
+            .filter(|query_match| self.predicates.satisfied(query_match, source))
+        {
+            let properties = self.query.property_settings(query_match.pattern_index);
+            let property = |key: &str| properties.iter().find(|prop| &*prop.key == key);
+
+            let language = query_match
+                .captures
+                .iter()
+                .find(|capture| Some(capture.index) == self.language_capture)
+                .and_then(|capture| capture.node.utf8_text(source).ok())
+                .map(|text| text.to_string())
+                .or_else(|| {
+                    property("injection.language")
+                        .and_then(|prop| prop.value.as_deref())
+                        .map(|value| value.to_string())
+                });
+
+            let language = match language {
+                Some(language) => language,
+                None => continue,
+            };
+
+            let include_children = property("injection.include-children").is_some();
+
+            let mut ranges = Vec::new();
+            for capture in query_match.captures {
+                if capture.index == content_capture {
+                    content_ranges(capture.node, include_children, &mut ranges);
+                }
+            }
+
+            if property("injection.combined").is_some() {
+                combined
+                    .entry((query_match.pattern_index, language))
+                    .or_default()
+                    .extend(ranges);
+            } else {
+                out.push((language, ranges));
             }
+        }
+
+        out.extend(
+            combined
+                .into_iter()
+                .map(|((_, language), ranges)| (language, ranges)),
+        );
+
+        for (_, ranges) in out.iter_mut() {
+            normalize_ranges(ranges);
+        }
+        out.retain(|(_, ranges)| !ranges.is_empty());
+
+        out
+    }
+}
+
+/// The parts of `node` to parse as an injection. Unless we were asked to
+/// include them, the node's children are left out, so that (for example)
+/// the interpolations in a template string aren't parsed as part of the
+/// string's contents.
+fn content_ranges(node: Node, include_children: bool, out: &mut Vec<Range>) {
+    if include_children {
+        out.push(node.range());
+        return;
+    }
+
+    let mut start_byte = node.start_byte();
+    let mut start_point = node.start_position();
+
+    let mut cursor = node.walk();
+    for child in node.children(&mut cursor) {
+        if child.start_byte() > start_byte {
+            out.push(Range {
+                start_byte,
+                end_byte: child.start_byte(),
+                start_point,
+                end_point: child.start_position(),
+            });
+        }
+
+        start_byte = child.end_byte();
+        start_point = child.end_position();
+    }
+
+    if node.end_byte() > start_byte {
+        out.push(Range {
+            start_byte,
+            end_byte: node.end_byte(),
+            start_point,
+            end_point: node.end_position(),
         });
+    }
+}
 
-        Extractor {
+/// `Parser::set_included_ranges` requires ranges to be in order and not
+/// overlap, so we sort them and drop any that overlap an earlier one.
+fn normalize_ranges(ranges: &mut Vec<Range>) {
+    ranges.sort_by_key(|range| (range.start_byte, range.end_byte));
+
+    let mut last_end = 0;
+    ranges.retain(|range| {
+        if range.start_byte < last_end || range.start_byte == range.end_byte {
+            false
+        } else {
+            last_end = range.end_byte;
+            true
+        }
+    });
+}
+
+/// The predicates we evaluate ourselves, on top of the `#eq?`, `#not-eq?`,
+/// `#match?`, and `#not-match?` that tree-sitter handles. Each one also has
+/// a `not-` version. These are the ones nvim-treesitter and helix queries
+/// use most.
+pub const SUPPORTED_PREDICATES: &[&str] = &[
+    "any-of?",
+    "contains?",
+    "has-ancestor?",
+    "has-parent?",
+    "lua-match?",
+];
+
+/// A general predicate from a query, ready to check against matches.
+#[derive(Debug)]
+struct Predicate {
+    capture: u32,
+    negated: bool,
+    test: PredicateTest,
+}
+
+#[derive(Debug)]
+enum PredicateTest {
+    /// the capture's text is exactly one of these
+    AnyOf(Vec<String>),
+    /// the capture's text contains at least one of these
+    Contains(Vec<String>),
+    /// some ancestor of the capture is one of these kinds
+    HasAncestor(Vec<String>),
+    /// the capture's parent is one of these kinds
+    HasParent(Vec<String>),
+    LuaMatch(Regex),
+}
+
+impl Predicate {
+    fn new(query_predicate: &QueryPredicate, capture_names: &[String]) -> Result<Predicate> {
+        let operator = &*query_predicate.operator;
+        let (negated, name) = match operator.strip_prefix("not-") {
+            Some(name) => (true, name),
+            None => (false, operator),
+        };
+
+        let (capture, values) = match query_predicate.args.split_first() {
+            Some((QueryPredicateArg::Capture(capture), rest)) => {
+                let values = rest
+                    .iter()
+                    .map(|arg| match arg {
+                        QueryPredicateArg::String(value) => Ok(value.to_string()),
+                        QueryPredicateArg::Capture(other) => bail!(
+                            "#{} compares @{} to strings, but got @{} too",
+                            operator,
+                            capture_names[*capture as usize],
+                            capture_names[*other as usize],
+                        ),
+                    })
+                    .collect::<Result<Vec<String>>>()?;
+
+                (*capture, values)
+            }
+            _ => bail!("#{} needs a capture as its first argument", operator),
+        };
+
+        if values.is_empty() {
+            bail!("#{} needs at least one value to compare against", operator)
+        }
+
+        let test = match name {
+            "any-of?" => PredicateTest::AnyOf(values),
+            "contains?" => PredicateTest::Contains(values),
+            "has-ancestor?" => PredicateTest::HasAncestor(values),
+            "has-parent?" => PredicateTest::HasParent(values),
+            "lua-match?" => {
+                if values.len() != 1 {
+                    bail!("#{} takes exactly one pattern", operator)
+                }
+
+                let regex = lua_pattern_to_regex(&values[0])
+                    .and_then(|pattern| Regex::new(&pattern).map_err(|err| err.into()))
+                    .with_context(|| {
+                        format!("could not understand the Lua pattern `{}`", values[0])
+                    })?;
+
+                PredicateTest::LuaMatch(regex)
+            }
+            _ => bail!(
+                "I don't know the #{} predicate. Besides tree-sitter's #eq? and #match?, I support {}, and their #not- versions.",
+                operator,
+                SUPPORTED_PREDICATES
+                    .iter()
+                    .map(|name| format!("#{}", name))
+                    .join(", ")
+            ),
+        };
+
+        Ok(Predicate {
+            capture,
+            negated,
+            test,
+        })
+    }
+
+    /// Check every node captured for the predicate's capture. A match
+    /// without any (say, from an optional capture) passes.
+    fn satisfied(&self, query_match: &QueryMatch, source: &[u8]) -> bool {
+        query_match
+            .captures
+            .iter()
+            .filter(|capture| capture.index == self.capture)
+            .all(|capture| self.test.check(capture.node, source) != self.negated)
+    }
+}
+
+impl PredicateTest {
+    fn check(&self, node: Node, source: &[u8]) -> bool {
+        let text = || node.utf8_text(source).unwrap_or_default();
+
+        match self {
+            PredicateTest::AnyOf(values) => values.iter().any(|value| value == text()),
+            PredicateTest::Contains(values) => {
+                values.iter().any(|value| text().contains(value.as_str()))
+            }
+            PredicateTest::HasAncestor(kinds) => {
+                let mut ancestor = node.parent();
+                while let Some(current) = ancestor {
+                    if kinds.iter().any(|kind| kind == current.kind()) {
+                        return true;
+                    }
+                    ancestor = current.parent();
+                }
+                false
+            }
+            PredicateTest::HasParent(kinds) => node
+                .parent()
+                .map(|parent| kinds.iter().any(|kind| kind == parent.kind()))
+                .unwrap_or(false),
+            PredicateTest::LuaMatch(regex) => regex.is_match(text()),
+        }
+    }
+}
+
+/// The general predicates for each pattern in a query.
+#[derive(Debug)]
+struct Predicates(Vec<Vec<Predicate>>);
+
+impl Predicates {
+    /// Compile the general predicates in `query`, complaining about any we
+    /// don't support. Directives (like nvim-treesitter's `#offset!`) are
+    /// errors unless `skip_directives` is set, in which case we ignore them.
+    fn new(query: &Query, skip_directives: bool) -> Result<Predicates> {
+        let capture_names = query.capture_names();
+
+        (0..query.pattern_count())
+            .map(|pattern| {
+                query
+                    .general_predicates(pattern)
+                    .iter()
+                    .filter(|predicate| !(skip_directives && predicate.operator.ends_with('!')))
+                    .map(|predicate| Predicate::new(predicate, capture_names))
+                    .collect::<Result<Vec<Predicate>>>()
+            })
+            .collect::<Result<Vec<Vec<Predicate>>>>()
+            .map(Predicates)
+    }
+
+    fn satisfied(&self, query_match: &QueryMatch, source: &[u8]) -> bool {
+        self.0[query_match.pattern_index]
+            .iter()
+            .all(|predicate| predicate.satisfied(query_match, source))
+    }
+}
+
+/// Translate a Lua pattern into an equivalent regex. Lua patterns are
+/// mostly a subset of regexes, except for `%` escapes and character
+/// classes, `-` (a lazy `*`), and `^` and `$` only being anchors at the
+/// very start and end. We don't support `%b` or `%f`, which don't have a
+/// regex equivalent.
+fn lua_pattern_to_regex(pattern: &str) -> Result<String> {
+    // in Lua, `.` matches anything, newlines included
+    let mut out = String::from("(?s)");
+    let mut in_set = false;
+    let mut chars = pattern.char_indices().peekable();
+
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '%' => {
+                let (_, escaped) = chars
+                    .next()
+                    .context("the pattern ends with a `%` that doesn't escape anything")?;
+
+                let class = match escaped.to_ascii_lowercase() {
+                    _ if escaped == 'b' || escaped == 'f' => {
+                        bail!("%{} isn't supported", escaped)
+                    }
+                    'a' => "a-zA-Z",
+                    'c' => "\\x00-\\x1f\\x7f",
+                    'd' => "0-9",
+                    'g' => "!-~",
+                    'l' => "a-z",
+                    'p' => "!-/:-@\\[-`{-~",
+                    's' => "\\t-\\r ",
+                    'u' => "A-Z",
+                    'w' => "a-zA-Z0-9",
+                    'x' => "0-9a-fA-F",
+                    _ => {
+                        out.push_str(&regex::escape(&escaped.to_string()));
+                        continue;
+                    }
+                };
+
+                // regex lets us nest classes inside sets, so these work the
+                // same inside and outside of `[]`.
+                if escaped.is_ascii_uppercase() {
+                    out.push_str(&format!("[^{}]", class));
+                } else if in_set {
+                    out.push_str(class);
+                } else {
+                    out.push_str(&format!("[{}]", class));
+                }
+            }
+
+            '[' if !in_set => {
+                in_set = true;
+                out.push('[');
+                if let Some((_, '^')) = chars.peek() {
+                    chars.next();
+                    out.push('^');
+                }
+                // a `]` right at the start of a set is a literal
+                if let Some((_, ']')) = chars.peek() {
+                    chars.next();
+                    out.push_str("\\]");
+                }
+            }
+            ']' if in_set => {
+                in_set = false;
+                out.push(']');
+            }
+            '-' if in_set => out.push('-'),
+
+            '-' => out.push_str("*?"),
+            '^' if index == 0 => out.push('^'),
+            '$' if chars.peek().is_none() => out.push('$'),
+            '.' | '*' | '+' | '?' | '(' | ')' if !in_set => out.push(c),
+
+            _ => out.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+
+    if in_set {
+        bail!("the pattern has a `[` without a matching `]`")
+    }
+
+    Ok(out)
+}
+
+impl Extractor {
+    /// `pattern_labels` has the label of the query each of `query`'s
+    /// patterns came from, in order.
+    pub fn new(language: Language, query: Query, pattern_labels: Vec<String>) -> Result<Extractor> {
+        let predicates = Predicates::new(&query, false)?;
+        let captures = query.capture_names().to_vec();
+        let filter = CaptureFilter::default();
+
+        Ok(Extractor {
             ts_language: language.ts_lang(),
             language,
             query,
+            predicates,
+            shown: captures.iter().map(|name| filter.shows(name)).collect(),
             captures,
-        }
+            pattern_labels,
+            regexes: Vec::new(),
+            injections: None,
+            scopes: Vec::new(),
+            file_conditions: Vec::new(),
+            max_matches: None,
+        })
+    }
+
+    /// Instead of outputting whole captures, look for a regex inside them
+    /// and output each place it matches. `regexes` has the regex (if any)
+    /// for each of the query's patterns, in order, like `pattern_labels`.
+    pub fn with_regexes(mut self, regexes: Vec<Option<Regex>>) -> Extractor {
+        self.regexes = regexes;
+        self
+    }
+
+    /// Look for code in other languages inside this one using an
+    /// `injections.scm`-style query, and extract matches from it too.
+    pub fn with_injections(mut self, query: Query) -> Result<Extractor> {
+        self.injections = Some(Injections::new(query)?);
+        Ok(self)
+    }
+
+    /// Only keep matches inside (or, if `inside` is false, outside)
+    /// something `query` captures. Each scope is checked on its own, so
+    /// with more than one, matches have to be inside all the `inside` ones
+    /// and outside all the others.
+    pub fn with_scope(mut self, query: Query, inside: bool) -> Result<Extractor> {
+        self.scopes.push(Scope::new(query, inside)?);
+        Ok(self)
+    }
+
+    /// Only search files where `query` matches somewhere (or, if `required`
+    /// is false, where it doesn't match anywhere.) With more than one, every
+    /// condition has to hold.
+    pub fn with_file_condition(mut self, query: Query, required: bool) -> Result<Extractor> {
+        self.file_conditions
+            .push(FileCondition::new(query, required)?);
+        Ok(self)
+    }
+
+    /// Stop after finding `max_matches` matches in a file (counting matches,
+    /// not captures.)
+    pub fn with_max_matches(mut self, max_matches: Option<usize>) -> Extractor {
+        self.max_matches = max_matches;
+        self
+    }
+
+    /// Only output the captures `filter` allows. We can't disable the rest
+    /// on the query, since predicates on a disabled capture always pass, so
+    /// we skip them when we extract matches instead.
+    pub fn with_capture_filter(mut self, filter: &CaptureFilter) -> Extractor {
+        self.shown = self
+            .captures
+            .iter()
+            .map(|name| filter.shows(name))
+            .collect();
+        self
     }
 
     pub fn language(&self) -> &Language {
         &self.language
     }
 
-    pub fn extract_from_file(
-        &self,
+    pub fn capture_names(&self) -> &[String] {
+        &self.captures
+    }
+
+    /// Extract matches from a file. `injected` finds the extractor to use
+    /// for code injected into the file in some other language; returning
+    /// `None` means that language won't be searched.
+    pub fn extract_from_file<'a>(
+        &'a self,
         path: &Path,
         parser: &mut Parser,
-    ) -> Result<Option<ExtractedFile>> {
-        let source = fs::read(&path).context("could not read file")?;
+        injected: &dyn Fn(&str) -> Option<&'a Extractor>,
+    ) -> Result<Option<ExtractedFile<'a>>> {
+        let source = fs::read(path).context("could not read file")?;
 
-        self.extract_from_text(Some(path), &source, parser)
+        self.extract_from_text(Some(path), &source, parser, injected)
     }
 
-    pub fn extract_from_text(
-        &self,
+    pub fn extract_from_text<'a>(
+        &'a self,
         path: Option<&Path>,
         source: &[u8],
         parser: &mut Parser,
-    ) -> Result<Option<ExtractedFile>> {
+        injected: &dyn Fn(&str) -> Option<&'a Extractor>,
+    ) -> Result<Option<ExtractedFile<'a>>> {
+        let tree = self.parse(source, &[], parser)?;
+
+        // the conditions don't depend on the matches, so we check them
+        // first and skip searching files that don't meet them.
+        if !self.conditions_hold(&tree, source) {
+            return Ok(None);
+        }
+
+        let mut extracted_matches = self.matches(&tree, source, false, 0)?;
+
+        self.extract_injections(&tree, source, parser, injected, 0, &mut extracted_matches)?;
+
+        self.apply_scopes(&tree, source, &mut extracted_matches);
+
+        if let Some(max_matches) = self.max_matches {
+            keep_first_matches(&mut extracted_matches, max_matches);
+        }
+
+        if extracted_matches.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(ExtractedFile {
+                file: path.map(|p| p.to_owned()),
+                file_type: self.language.name().to_string(),
+                matches: extracted_matches,
+            }))
+        }
+    }
+
+    fn conditions_hold(&self, tree: &Tree, source: &[u8]) -> bool {
+        self.file_conditions
+            .iter()
+            .all(|condition| condition.holds(tree.root_node(), source))
+    }
+
+    /// Drop matches that aren't where `--inside` and `--not-inside` say they
+    /// have to be. We look at whole matches rather than single captures, so
+    /// a match is never split up.
+    fn apply_scopes(&self, tree: &Tree, source: &[u8], matches: &mut Vec<ExtractedMatch>) {
+        if self.scopes.is_empty() || matches.is_empty() {
+            return;
+        }
+
+        let scopes: Vec<(bool, Vec<(Point, Point)>)> = self
+            .scopes
+            .iter()
+            .map(|scope| (scope.inside, scope.ranges(tree.root_node(), source)))
+            .collect();
+
+        let mut spans: HashMap<usize, (Point, Point)> = HashMap::new();
+        for extracted in matches.iter() {
+            spans
+                .entry(extracted.match_index)
+                .and_modify(|(start, end)| {
+                    *start = (*start).min(extracted.start);
+                    *end = (*end).max(extracted.end);
+                })
+                .or_insert((extracted.start, extracted.end));
+        }
+
+        let kept: HashSet<usize> = spans
+            .into_iter()
+            .filter(|(_, (start, end))| {
+                scopes.iter().all(|(inside, ranges)| {
+                    let contained = ranges
+                        .iter()
+                        .any(|(scope_start, scope_end)| scope_start <= start && end <= scope_end);
+
+                    contained == *inside
+                })
+            })
+            .map(|(match_index, _)| match_index)
+            .collect();
+
+        matches.retain(|extracted| kept.contains(&extracted.match_index));
+    }
+
+    /// Parse `source`, or just the given ranges of it. Passing no ranges
+    /// parses the whole thing.
+    fn parse(&self, source: &[u8], ranges: &[Range], parser: &mut Parser) -> Result<Tree> {
         parser
             .set_language(self.ts_language)
             .context("could not set language")?;
 
-        let tree = parser
-            .parse(&source, None)
+        // parsers get reused across files, so we always set this (even if
+        // it's empty) to clear out whatever the last injection used.
+        parser
+            .set_included_ranges(ranges)
+            .context("could not restrict parsing to the injected ranges")?;
+
+        parser
+            .parse(source, None)
             // note: this could be a timeout or cancellation, but we don't set
             // that so we know it's always a language error. Buuuut we also
             // always set the language above so if this happens we also know
             // it's an internal error.
-            .context(
-                "could not parse to a tree. This is an internal error and should be reported.",
+            .context("could not parse to a tree. This is an internal error and should be reported.")
+    }
+
+    fn extract_injections<'a>(
+        &'a self,
+        tree: &Tree,
+        source: &[u8],
+        parser: &mut Parser,
+        injected: &dyn Fn(&str) -> Option<&'a Extractor>,
+        depth: usize,
+        out: &mut Vec<ExtractedMatch<'a>>,
+    ) -> Result<()> {
+        let injections = match &self.injections {
+            Some(injections) if depth < MAX_INJECTION_DEPTH => injections,
+            _ => return Ok(()),
+        };
+
+        for (language, ranges) in injections.ranges(tree.root_node(), source) {
+            let extractor = match injected(&language) {
+                Some(extractor) => extractor,
+                None => continue,
+            };
+
+            let injected_tree = extractor
+                .parse(source, &ranges, parser)
+                .with_context(|| format!("could not parse code injected as {}", language))?;
+
+            // code injected in a language's own files (like Rust in Rust
+            // macros) is part of a file whose conditions we already checked,
+            // and the host applies the same scopes to it. Otherwise, the
+            // injected code stands in for a file of its own: its language's
+            // conditions decide whether we search it, and its scopes say
+            // which of its matches we keep.
+            let own_language = std::ptr::eq(extractor, self);
+            if !own_language && !extractor.conditions_hold(&injected_tree, source) {
+                continue;
+            }
+
+            let batch_start = out.len();
+
+            // keep numbering matches from where the last batch left off, so
+            // captures from different matches never get grouped together
+            let first_match = out.last().map_or(0, |extracted| extracted.match_index + 1);
+            out.extend(extractor.matches(&injected_tree, source, true, first_match)?);
+
+            extractor.extract_injections(
+                &injected_tree,
+                source,
+                parser,
+                injected,
+                depth + 1,
+                out,
             )?;
 
-        let mut cursor = QueryCursor::new();
+            if !own_language {
+                let mut batch = out.split_off(batch_start);
+                extractor.apply_scopes(&injected_tree, source, &mut batch);
+                out.append(&mut batch);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the captures of every match in `tree`, in order. Matches are
+    /// numbered starting at `first_match` so we can tell which captures
+    /// came from the same one.
+    fn matches(
+        &self,
+        tree: &Tree,
+        source: &[u8],
+        injected: bool,
+        first_match: usize,
+    ) -> Result<Vec<ExtractedMatch<'_>>> {
+        let mut out = Vec::new();
+        let mut match_index = first_match;
+
+        // we can stop looking once we have enough matches, unless scopes
+        // might drop some of them later. (`QueryCursor::set_match_limit`
+        // looks like it would do this for us, but it limits how many
+        // matches can be in progress at once, not how many we get back.)
+        let limit = self.max_matches.filter(|_| self.scopes.is_empty());
 
-        let extracted_matches = cursor
+        // scopes for `--in` can be nested (like blocks inside blocks) and
+        // we only want to report each place the regex matches once.
+        let mut seen_regex_matches = HashSet::new();
+
+        let mut cursor = QueryCursor::new();
+        for query_match in cursor
             .matches(&self.query, tree.root_node(), source)
-            .flat_map(|query_match| query_match.captures)
+            .filter(|query_match| self.predicates.satisfied(query_match, source))
+        {
+            let query = self
+                .pattern_labels
+                .get(query_match.pattern_index)
+                .map(|label| label.as_str())
+                .unwrap_or_default();
+
+            let regex = self
+                .regexes
+                .get(query_match.pattern_index)
+                .and_then(Option::as_ref);
+
+            let shown_before = out.len();
+
+            // see `with_capture_filter` for why we don't disable these on
+            // the query instead.
+            //
             // note: the casts here could potentially break if run on a 16-bit
             // microcontroller. I don't think this is a huge problem, though,
             // since even the gnarliest queries I've written have something on
             // the order of 20 matches. Nowhere close to 2^16!
-            .map(|capture| {
+            for capture in query_match
+                .captures
+                .iter()
+                .filter(|capture| self.shown[capture.index as usize])
+            {
                 let name = &self.captures[capture.index as usize];
                 let node = capture.node;
-                let text = match node
+                let text = node
                     .utf8_text(source)
-                    .map(|unowned| unowned.to_string())
-                    .context("could not extract text from capture")
-                {
-                    Ok(text) => text,
-                    Err(problem) => return Err(problem),
-                };
+                    .context("could not extract text from capture")?;
 
-                Ok(ExtractedMatch {
-                    kind: node.kind(),
-                    name,
-                    text,
-                    start: node.start_position(),
-                    end: node.end_position(),
-                })
-            })
-            .collect::<Result<Vec<ExtractedMatch>>>()?;
+                let extracted =
+                    |text: &str, start: Point, end: Point, match_index: usize| ExtractedMatch {
+                        kind: node.kind(),
+                        name,
+                        query,
+                        text: text.to_string(),
+                        start,
+                        end,
+                        language: if injected {
+                            Some(self.language.name().to_string())
+                        } else {
+                            None
+                        },
+                        match_index,
+                    };
 
-        if extracted_matches.is_empty() {
-            Ok(None)
-        } else {
-            Ok(Some(ExtractedFile {
-                file: path.map(|p| p.to_owned()),
-                file_type: self.language.name().to_string(),
-                matches: extracted_matches,
-            }))
+                match regex {
+                    None => out.push(extracted(
+                        text,
+                        node.start_position(),
+                        node.end_position(),
+                        match_index,
+                    )),
+
+                    // each place the regex matches is a match of its own
+                    Some(regex) => {
+                        for found in regex.find_iter(text) {
+                            let key = (
+                                query_match.pattern_index,
+                                node.start_byte() + found.start(),
+                                node.start_byte() + found.end(),
+                            );
+                            if !seen_regex_matches.insert(key) {
+                                continue;
+                            }
+
+                            out.push(extracted(
+                                found.as_str(),
+                                point_within(node, text, found.start()),
+                                point_within(node, text, found.end()),
+                                match_index,
+                            ));
+                            match_index += 1;
+                        }
+                    }
+                }
+            }
+
+            // a match whose captures are all hidden doesn't count toward the
+            // limit, since nobody will see it
+            if regex.is_none() && out.len() > shown_before {
+                match_index += 1;
+            }
+
+            if limit.is_some_and(|limit| match_index - first_match >= limit) {
+                break;
+            }
         }
+
+        Ok(out)
+    }
+}
+
+/// Drop everything but the captures from the first `count` matches in the
+/// file. Matches in injected code come after all the host language's
+/// matches, so "first" goes by where each match starts rather than by its
+/// place in `matches`.
+fn keep_first_matches(matches: &mut Vec<ExtractedMatch>, count: usize) {
+    let mut starts: HashMap<usize, Point> = HashMap::new();
+    for extracted in matches.iter() {
+        starts
+            .entry(extracted.match_index)
+            .and_modify(|start| *start = (*start).min(extracted.start))
+            .or_insert(extracted.start);
+    }
+
+    let kept: HashSet<usize> = starts
+        .into_iter()
+        .map(|(match_index, start)| (start, match_index))
+        .sorted()
+        .take(count)
+        .map(|(_, match_index)| match_index)
+        .collect();
+
+    matches.retain(|extracted| kept.contains(&extracted.match_index));
+}
+
+/// Where the byte at `offset` in `text` (the text of `node`) is in the
+/// file. Like tree-sitter's, columns are in bytes.
+fn point_within(node: Node, text: &str, offset: usize) -> Point {
+    let start = node.start_position();
+    let before = &text[..offset];
+
+    match before.rfind('\n') {
+        Some(newline) => Point {
+            row: start.row + before.matches('\n').count(),
+            column: offset - newline - 1,
+        },
+        None => Point {
+            row: start.row,
+            column: start.column + offset,
+        },
     }
 }
 
@@ -117,6 +996,75 @@ pub struct ExtractedFile<'query> {
     pub(crate) matches: Vec<ExtractedMatch<'query>>,
 }
 
+impl<'query> ExtractedFile<'query> {
+    /// The labels of every query with a match in this file, in the order
+    /// they first matched.
+    pub fn queries(&self) -> Vec<&'query str> {
+        self.matches
+            .iter()
+            .map(|extraction| extraction.query)
+            .unique()
+            .collect()
+    }
+
+    /// How many matches there are in this file. This can be fewer than
+    /// `matches.len()`, since that has one entry per capture.
+    pub fn match_count(&self) -> usize {
+        self.matches
+            .iter()
+            .map(|extraction| extraction.match_index)
+            .unique()
+            .count()
+    }
+
+    /// Only keep the first `count` matches.
+    pub fn truncate(&mut self, count: usize) {
+        keep_first_matches(&mut self.matches, count)
+    }
+
+    /// Put the captures from each match together.
+    pub fn grouped(&self) -> GroupedFile<'_> {
+        let matches = self
+            .matches
+            .iter()
+            .group_by(|extraction| extraction.match_index)
+            .into_iter()
+            .map(|(_, captures)| {
+                let captures: Vec<&ExtractedMatch> = captures.collect();
+
+                GroupedMatch {
+                    query: captures[0].query,
+                    start: captures.iter().map(|capture| capture.start).min().unwrap(),
+                    end: captures.iter().map(|capture| capture.end).max().unwrap(),
+                    language: captures[0].language.as_deref(),
+                    captures: captures
+                        .iter()
+                        .map(|capture| {
+                            (
+                                capture.name,
+                                GroupedCapture {
+                                    kind: capture.kind,
+                                    text: &capture.text,
+                                    start: capture.start,
+                                    end: capture.end,
+                                },
+                            )
+                        })
+                        .collect(),
+                }
+            })
+            .collect();
+
+        GroupedFile {
+            file: self.file.as_deref(),
+            file_type: &self.file_type,
+            matches,
+        }
+    }
+}
+
+/// One line per match. The alternate form (`{:#}`) adds the label of the
+/// query that matched before the capture name.
 impl<'query> Display for ExtractedFile<'query> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         // TODO: is there a better way to do this unwrapping? This implementation
@@ -130,15 +1078,19 @@ impl<'query> Display for ExtractedFile<'query> {
             .unwrap_or("NO FILE");
 
         for extraction in &self.matches {
-            writeln!(
+            write!(
                 f,
-                "{}:{}:{}:{}:{}",
+                "{}:{}:{}:",
                 filename,
                 extraction.start.row + 1,
                 extraction.start.column + 1,
-                extraction.name,
-                extraction.text
-            )?
+            )?;
+
+            if f.alternate() {
+                write!(f, "{}:", extraction.query)?;
+            }
+
+            writeln!(f, "{}:{}", extraction.name, extraction.text)?
         }
 
         Ok(())
@@ -149,11 +1101,149 @@ impl<'query> Display for ExtractedFile<'query> {
 pub struct ExtractedMatch<'query> {
     kind: &'static str,
     name: &'query str,
+    /// The label of the query that matched
+    query: &'query str,
     text: String,
     #[serde(serialize_with = "serialize_point")]
     pub(crate) start: Point,
     #[serde(serialize_with = "serialize_point")]
     pub(crate) end: Point,
+    /// The language of the code this match came from, if it was injected
+    /// into the file rather than being in the file's own language.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    language: Option<String>,
+    /// Which match in the file this capture is part of. Captures from the
+    /// same match are always next to each other.
+    #[serde(skip)]
+    match_index: usize,
+}
+
+/// The matches in a file with each match's captures together, instead of
+/// one row per capture. See `ExtractedFile::grouped`.
+#[derive(Debug, Serialize)]
+pub struct GroupedFile<'a> {
+    file: Option<&'a Path>,
+    file_type: &'a str,
+    matches: Vec<GroupedMatch<'a>>,
+}
+
+/// Everything one match captured. A name can be captured more than once
+/// (with a quantifier, like `(parameter)* @param`) so each name has a
+/// list of captures, even when there's only one.
+#[derive(Debug)]
+pub struct GroupedMatch<'a> {
+    query: &'a str,
+    start: Point,
+    end: Point,
+    language: Option<&'a str>,
+    /// In the order they were captured
+    captures: Vec<(&'a str, GroupedCapture<'a>)>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupedCapture<'a> {
+    kind: &'static str,
+    text: &'a str,
+    #[serde(serialize_with = "serialize_point")]
+    start: Point,
+    #[serde(serialize_with = "serialize_point")]
+    end: Point,
+}
+
+impl<'a> Serialize for GroupedMatch<'a> {
+    fn serialize<S>(&self, sz: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut out = sz.serialize_struct("GroupedMatch", 5)?;
+        out.serialize_field("query", self.query)?;
+        out.serialize_field("start", &SerializePoint(&self.start))?;
+        out.serialize_field("end", &SerializePoint(&self.end))?;
+        out.serialize_field("captures", &CapturesByName(&self.captures))?;
+        if let Some(language) = self.language {
+            out.serialize_field("language", language)?;
+        } else {
+            out.skip_field("language")?;
+        }
+        out.end()
+    }
+}
+
+/// Captures as a map from name to every capture with that name, with the
+/// names in the order they were first captured.
+struct CapturesByName<'a, 'b>(&'b [(&'a str, GroupedCapture<'a>)]);
+
+impl<'a, 'b> Serialize for CapturesByName<'a, 'b> {
+    fn serialize<S>(&self, sz: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let names: Vec<&str> = self.0.iter().map(|(name, _)| *name).unique().collect();
+
+        let mut out = sz.serialize_map(Some(names.len()))?;
+        for name in names {
+            let captures: Vec<&GroupedCapture> = self
+                .0
+                .iter()
+                .filter(|(other, _)| *other == name)
+                .map(|(_, capture)| capture)
+                .collect();
+            out.serialize_entry(name, &captures)?;
+        }
+        out.end()
+    }
+}
+
+struct SerializePoint<'a>(&'a Point);
+
+impl<'a> Serialize for SerializePoint<'a> {
+    fn serialize<S>(&self, sz: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serialize_point(self.0, sz)
+    }
+}
+
+/// One line per match, with each capture as `name="text"` (the text is
+/// quoted and escaped like a JSON string, so it never spans lines.) Like
+/// `ExtractedFile`, the alternate form adds the label of the query that
+/// matched.
+impl<'a> Display for GroupedFile<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let filename = self
+            .file
+            .map(|f| f.to_str().unwrap_or("NON-UTF8 FILENAME"))
+            .unwrap_or("NO FILE");
+
+        for grouped in &self.matches {
+            write!(
+                f,
+                "{}:{}:{}:",
+                filename,
+                grouped.start.row + 1,
+                grouped.start.column + 1,
+            )?;
+
+            if f.alternate() {
+                write!(f, "{}:", grouped.query)?;
+            }
+
+            for (index, (name, capture)) in grouped.captures.iter().enumerate() {
+                // serializing a string to JSON can't fail
+                let text = serde_json::to_string(capture.text).map_err(|_| fmt::Error)?;
+                if index == 0 {
+                    write!(f, "{}={}", name, text)?;
+                } else {
+                    write!(f, " {}={}", name, text)?;
+                }
+            }
+
+            writeln!(f)?
+        }
+
+        Ok(())
+    }
 }
 
 fn serialize_point<S>(point: &Point, sz: S) -> Result<S::Ok, S::Error>
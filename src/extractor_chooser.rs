@@ -1,4 +1,7 @@
 use crate::extractor::Extractor;
+use crate::file_types::FileTypes;
+use crate::language::Aliases;
+use crate::language_detection::LanguageDetector;
 use anyhow::{bail, Context, Result};
 use ignore::types::{Types, TypesBuilder};
 use ignore::DirEntry;
@@ -6,47 +9,134 @@ use std::collections::HashMap;
 
 pub struct ExtractorChooser<'extractor> {
     matcher: Types,
-    extractors: HashMap<&'extractor str, &'extractor Extractor>,
+    /// Every file type we know about, whether or not we're searching it.
+    /// Files matching one of these are what their path says they are, so
+    /// we don't open them to look for shebangs or modelines.
+    known: Types,
+    extractors: HashMap<String, &'extractor Extractor>,
+    by_language: HashMap<&'extractor str, &'extractor Extractor>,
+    detector: LanguageDetector<'extractor>,
 }
 
 impl<'extractor> ExtractorChooser<'extractor> {
-    pub fn from_extractors(extractors: &[Extractor]) -> Result<ExtractorChooser> {
+    pub fn from_extractors<'a>(
+        extractors: &'a [Extractor],
+        file_types: &FileTypes,
+        aliases: &'a Aliases,
+    ) -> Result<ExtractorChooser<'a>> {
         let mut types_builder = TypesBuilder::new();
-        types_builder.add_defaults();
 
         let mut names_to_extractors = HashMap::with_capacity(extractors.len());
+        let mut by_language = HashMap::with_capacity(extractors.len());
 
-        for extractor in extractors {
-            let name = extractor.language().name_for_types_builder();
-            types_builder.select(name);
+        for (index, extractor) in extractors.iter().enumerate() {
+            let language = extractor.language().name();
+
+            let globs = file_types.globs_for(language);
+            if globs.is_empty() {
+                bail!(
+                    "I don't know which files to search with the {} grammar. Tell me with `--type-add {}:GLOB` or under `[file-types]` in the config file.",
+                    language,
+                    language,
+                )
+            }
+
+            // `ignore` only allows letters and numbers in type names, but
+            // grammar names can have other stuff (like `c_sharp`.) We never
+            // show these names to anyone, so we just number them instead.
+            let name = format!("lang{}", index);
+            for glob in globs {
+                types_builder
+                    .add(&name, &glob)
+                    .with_context(|| format!("could not add `{}` for {}", glob, language))?;
+            }
+            types_builder.select(&name);
 
             // a little reminder: insert returns the old value if the key was
             // already present
-            if names_to_extractors.insert(name, extractor).is_some() {
+            if names_to_extractors.insert(name, extractor).is_some()
+                || by_language.insert(language, extractor).is_some()
+            {
                 bail!("got a duplicate query. This should not have happened. Please report it!")
             }
         }
 
+        let mut known_builder = TypesBuilder::new();
+        for (index, language) in file_types.languages().enumerate() {
+            let name = format!("known{}", index);
+            for glob in file_types.globs_for(language) {
+                known_builder
+                    .add(&name, &glob)
+                    .with_context(|| format!("could not add `{}` for {}", glob, language))?;
+            }
+            known_builder.select(&name);
+        }
+
         Ok(ExtractorChooser {
+            known: known_builder
+                .build()
+                .context("could not build a matcher for known file types")?,
             matcher: types_builder
                 .build()
                 .context("could not build a filetype matcher using provided extractors")?,
             extractors: names_to_extractors,
+            by_language,
+            detector: LanguageDetector::new(aliases),
         })
     }
 
-    pub fn extractor_for(&self, entry: &DirEntry) -> Option<&Extractor> {
-        let is_dir = entry.file_type().map(|ft| ft.is_dir()).unwrap_or(true);
-        let matched = self.matcher.matched(entry.path(), is_dir);
+    /// Get the extractor for a language by its grammar's name (not an alias.)
+    pub fn extractor_for_language(&self, language: &str) -> Option<&'extractor Extractor> {
+        self.by_language.get(language).copied()
+    }
 
-        if !matched.is_whitelist() {
+    /// Choose an extractor by the file's path. If that doesn't match one of
+    /// the languages we're searching, we look at `.gitattributes`, and if
+    /// the path doesn't match any type we know about (like a script with no
+    /// extension) at the file itself. See `LanguageDetector`.
+    pub fn extractor_for(&self, entry: &DirEntry) -> Option<&'extractor Extractor> {
+        let is_dir = entry.file_type().map(|ft| ft.is_dir()).unwrap_or(true);
+        if is_dir {
             return None;
         }
 
-        matched
-            .inner()
-            .and_then(|glob| glob.file_type_def())
-            .and_then(|def| self.extractors.get(def.name()))
-            .copied()
+        let matched = self.matcher.matched(entry.path(), is_dir);
+        if matched.is_whitelist() {
+            return matched
+                .inner()
+                .and_then(|glob| glob.file_type_def())
+                .and_then(|def| self.extractors.get(def.name()))
+                .copied();
+        }
+
+        let detected = if self.known.matched(entry.path(), is_dir).is_whitelist() {
+            self.detector.detect_from_git_attributes(entry.path())
+        } else {
+            self.detector.detect(entry.path())
+        };
+
+        detected.and_then(|language| self.extractor_for_language(&language))
+    }
+}
+
+#[cfg(all(test, feature = "bundled-grammars"))]
+mod tests {
+    use super::*;
+    use crate::language::Registry;
+
+    #[test]
+    fn languages_without_globs_are_an_error() {
+        let registry = Registry::new(Vec::new(), Vec::new(), Aliases::new());
+        let language = registry.get("rust").unwrap();
+        let query = language.query("(function_item) @function").unwrap();
+        let extractors = vec![Extractor::new(language, query, Vec::new()).unwrap()];
+
+        match ExtractorChooser::from_extractors(&extractors, &FileTypes::empty(), &Aliases::new()) {
+            Ok(_) => panic!("expected an error for a language with no globs"),
+            Err(err) => assert_eq!(
+                err.to_string(),
+                "I don't know which files to search with the rust grammar. Tell me with `--type-add rust:GLOB` or under `[file-types]` in the config file."
+            ),
+        }
     }
 }
@@ -0,0 +1,208 @@
+use anyhow::{bail, Context, Result};
+use ignore::types::TypesBuilder;
+use itertools::Itertools;
+use std::collections::HashMap;
+
+/// Grammars whose language name isn't the name `ignore` uses for the same
+/// files. Anything not listed here is looked up in `ignore`'s defaults under
+/// its own name, which covers most grammars (`go`, `lua`, `rust`, `toml`...)
+const IGNORE_TYPE_NAMES: &[(&str, &str)] = &[
+    ("bash", "sh"),
+    ("c_sharp", "csharp"),
+    ("commonlisp", "lisp"),
+    ("dockerfile", "docker"),
+    ("javascript", "js"),
+    ("latex", "tex"),
+    ("proto", "protobuf"),
+    ("python", "py"),
+    ("terraform", "tf"),
+    ("typescript", "ts"),
+];
+
+/// Grammars `ignore` doesn't know about at all.
+const EXTRA_GLOBS: &[(&str, &[&str])] = &[
+    ("gleam", &["*.gleam"]),
+    ("graphql", &["*.graphql", "*.gql"]),
+    ("hcl", &["*.hcl"]),
+    ("jsonc", &["*.jsonc"]),
+    ("scheme", &["*.scm", "*.ss"]),
+    ("svelte", &["*.svelte"]),
+    ("tsx", &["*.tsx"]),
+    ("vue", &["*.vue"]),
+];
+
+/// Which files belong to which language. This starts out with the built-in
+/// defaults above, and users can add globs for any language (including ones
+/// we've never heard of) in the config file or with `--type-add`.
+#[derive(Debug, Clone)]
+pub struct FileTypes {
+    defaults: HashMap<String, Vec<String>>,
+    added: HashMap<String, Vec<String>>,
+}
+
+impl FileTypes {
+    pub fn new() -> FileTypes {
+        let mut types_builder = TypesBuilder::new();
+        types_builder.add_defaults();
+
+        let mut defaults: HashMap<String, Vec<String>> = types_builder
+            .definitions()
+            .into_iter()
+            .map(|def| (def.name().to_string(), def.globs().to_vec()))
+            .collect();
+
+        // `ignore` sometimes lumps together files that we have separate
+        // grammars for (its `ts` type includes `*.tsx`, for example.) Those
+        // files should only go to the grammar that claims them in
+        // `EXTRA_GLOBS`, since the other one can't parse them.
+        for (language, ignore_name) in IGNORE_TYPE_NAMES {
+            if let Some(globs) = defaults.get(*ignore_name) {
+                let globs = globs
+                    .iter()
+                    .filter(|glob| {
+                        !EXTRA_GLOBS
+                            .iter()
+                            .any(|(_, extra)| extra.contains(&glob.as_str()))
+                    })
+                    .cloned()
+                    .collect();
+                defaults.insert(language.to_string(), globs);
+            }
+        }
+
+        for (language, globs) in EXTRA_GLOBS {
+            defaults.insert(
+                language.to_string(),
+                globs.iter().map(|glob| glob.to_string()).collect(),
+            );
+        }
+
+        FileTypes {
+            defaults,
+            added: HashMap::new(),
+        }
+    }
+
+    /// A table with no globs at all, not even the defaults.
+    #[cfg(all(test, feature = "bundled-grammars"))]
+    pub fn empty() -> FileTypes {
+        FileTypes {
+            defaults: HashMap::new(),
+            added: HashMap::new(),
+        }
+    }
+
+    /// Search files matching `glob` with `language`'s queries, in addition to
+    /// whatever files we'd already search. `language` should be the grammar's
+    /// name, not an alias.
+    pub fn add(&mut self, language: &str, glob: &str) {
+        self.added
+            .entry(language.to_ascii_lowercase())
+            .or_default()
+            .push(glob.to_string());
+    }
+
+    /// Split a definition in `--type-add` form, like `lua:*.rockspec`, into
+    /// a language and a glob.
+    pub fn parse_def(def: &str) -> Result<(&str, &str)> {
+        let (language, glob) = def
+            .split_once(':')
+            .with_context(|| format!("`{}` should look like LANGUAGE:GLOB", def))?;
+
+        if language.is_empty() || glob.is_empty() {
+            bail!("`{}` should look like LANGUAGE:GLOB", def)
+        }
+
+        Ok((language, glob))
+    }
+
+    /// Every language (or `ignore` type) we know any globs for.
+    pub fn languages(&self) -> impl Iterator<Item = &str> {
+        self.defaults
+            .keys()
+            .chain(self.added.keys())
+            .map(String::as_str)
+            .unique()
+    }
+
+    /// All the globs for a language, in the order they were defined.
+    pub fn globs_for(&self, language: &str) -> Vec<String> {
+        self.defaults
+            .get(language)
+            .into_iter()
+            .chain(self.added.get(language))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for FileTypes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_def_splits_language_and_glob() {
+        assert_eq!(
+            FileTypes::parse_def("lua:*.rockspec").unwrap(),
+            ("lua", "*.rockspec")
+        );
+    }
+
+    #[test]
+    fn parse_def_only_splits_on_the_first_colon() {
+        assert_eq!(
+            FileTypes::parse_def("make:build:*.mk").unwrap(),
+            ("make", "build:*.mk")
+        );
+    }
+
+    #[test]
+    fn parse_def_rejects_malformed_defs() {
+        for def in ["lua", ":*.rockspec", "lua:", ":"] {
+            assert_eq!(
+                FileTypes::parse_def(def).unwrap_err().to_string(),
+                format!("`{}` should look like LANGUAGE:GLOB", def),
+            );
+        }
+    }
+
+    #[test]
+    fn added_globs_come_after_defaults() {
+        let mut file_types = FileTypes::new();
+        file_types.add("Rust", "*.rs.in");
+
+        let globs = file_types.globs_for("rust");
+        assert_eq!(globs.first().map(String::as_str), Some("*.rs"));
+        assert_eq!(globs.last().map(String::as_str), Some("*.rs.in"));
+    }
+
+    #[test]
+    fn added_languages_are_known() {
+        let mut file_types = FileTypes::new();
+        assert!(file_types.globs_for("klingon").is_empty());
+
+        file_types.add("klingon", "*.tlh");
+        assert_eq!(file_types.globs_for("klingon"), vec!["*.tlh"]);
+        assert!(file_types.languages().any(|language| language == "klingon"));
+    }
+
+    #[test]
+    fn tsx_files_only_go_to_tsx() {
+        let file_types = FileTypes::new();
+
+        assert!(file_types
+            .globs_for("typescript")
+            .contains(&"*.ts".to_string()));
+        assert!(!file_types
+            .globs_for("typescript")
+            .contains(&"*.tsx".to_string()));
+        assert_eq!(file_types.globs_for("tsx"), vec!["*.tsx"]);
+    }
+}
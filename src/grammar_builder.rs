@@ -0,0 +1,249 @@
+use crate::language::{Aliases, Language, Registry, DYLIB_EXTENSION};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+#[derive(Debug)]
+pub struct GrammarBuilder {
+    /// A grammar checkout, containing `src/parser.c`
+    source_dir: PathBuf,
+    name: Option<String>,
+    out_dir: PathBuf,
+}
+
+/// The only bit of `src/grammar.json` we care about.
+#[derive(Deserialize)]
+struct GrammarJson {
+    name: String,
+}
+
+impl GrammarBuilder {
+    pub fn new(source_dir: PathBuf, name: Option<String>, out_dir: PathBuf) -> GrammarBuilder {
+        GrammarBuilder {
+            source_dir,
+            name,
+            out_dir,
+        }
+    }
+
+    /// Compile the grammar into a library in the output directory and make
+    /// sure we can load it. Returns the loaded language.
+    pub fn build(&self) -> Result<Language> {
+        let src_dir = self.source_dir.join("src");
+        let parser = src_dir.join("parser.c");
+        if !parser.is_file() {
+            bail!(
+                "{} doesn't look like a tree-sitter grammar: there's no src/parser.c. If the grammar lives in a subdirectory (like tree-sitter-typescript/tsx), point me there instead. If there's only a grammar.js, run `tree-sitter generate` first.",
+                self.source_dir.display()
+            )
+        }
+
+        let name = self.name()?;
+
+        let mut sources = vec![parser];
+        let mut needs_cxx = false;
+        if src_dir.join("scanner.c").is_file() {
+            sources.push(src_dir.join("scanner.c"));
+        } else if src_dir.join("scanner.cc").is_file() {
+            sources.push(src_dir.join("scanner.cc"));
+            needs_cxx = true;
+        }
+
+        let build_dir = self.out_dir.join("build").join(&name);
+        fs::create_dir_all(&build_dir)
+            .with_context(|| format!("could not create {}", build_dir.display()))?;
+
+        let mut objects = Vec::with_capacity(sources.len());
+        for source in &sources {
+            let is_cxx = source.extension().map(|ext| ext == "cc").unwrap_or(false);
+            let object = build_dir.join(source.with_extension("o").file_name().unwrap());
+
+            let mut command = Command::new(compiler(is_cxx));
+            command
+                .arg("-c")
+                .arg("-fPIC")
+                .arg("-O2")
+                .arg("-I")
+                .arg(&src_dir)
+                .arg(source)
+                .arg("-o")
+                .arg(&object);
+            run(command).with_context(|| format!("could not compile {}", source.display()))?;
+
+            objects.push(object);
+        }
+
+        // we link to a temporary name and then rename so that a failed build
+        // never clobbers a library that was working before.
+        let library = self.out_dir.join(format!("{}.{}", name, DYLIB_EXTENSION));
+        let temp_library = build_dir.join(format!("{}.{}", name, DYLIB_EXTENSION));
+
+        let mut command = Command::new(compiler(needs_cxx));
+        command
+            .arg(if cfg!(target_os = "macos") {
+                "-dynamiclib"
+            } else {
+                "-shared"
+            })
+            .args(&objects)
+            .arg("-o")
+            .arg(&temp_library);
+        run(command).context("could not link the grammar library")?;
+
+        // this is the same path we use for every other grammar, so if it
+        // loads here it'll work for queries too. We check before moving it
+        // into place so that a library we can't use (say, because it has an
+        // ABI version we don't support) doesn't replace one we can.
+        Registry::new(vec![build_dir.clone()], Vec::new(), Aliases::new())
+            .get(&name)
+            .context("built the library, but could not load it")?;
+
+        fs::rename(&temp_library, &library)
+            .with_context(|| format!("could not move the library to {}", library.display()))?;
+
+        // `--node-types` looks for this next to the library. Not every
+        // checkout has one, and the grammar works without it, so we only
+        // copy it if it's there.
+        let node_types = src_dir.join("node-types.json");
+        if node_types.is_file() {
+            let dest = self.out_dir.join(format!("{}.node-types.json", name));
+            fs::copy(&node_types, &dest)
+                .with_context(|| format!("could not copy node-types.json to {}", dest.display()))?;
+        }
+
+        // the objects aren't useful after linking. If cleaning them up fails
+        // the build still worked, so we don't complain about it.
+        let _ = fs::remove_dir_all(&build_dir);
+
+        Registry::new(vec![self.out_dir.clone()], Vec::new(), Aliases::new())
+            .get(&name)
+            .context("built the library, but could not load it")
+    }
+
+    /// What to call the grammar. This has to match the `tree_sitter_NAME`
+    /// symbol in the library, so we ask the generated grammar before guessing
+    /// from the directory name.
+    fn name(&self) -> Result<String> {
+        if let Some(name) = &self.name {
+            return Ok(name.to_ascii_lowercase());
+        }
+
+        let grammar_json = self.source_dir.join("src").join("grammar.json");
+        if let Ok(raw) = fs::read_to_string(&grammar_json) {
+            let grammar: GrammarJson = serde_json::from_str(&raw).with_context(|| {
+                format!("could not read the name from {}", grammar_json.display())
+            })?;
+            return Ok(grammar.name.to_ascii_lowercase());
+        }
+
+        let dir_name = self
+            .source_dir
+            .canonicalize()
+            .ok()
+            .and_then(|dir| {
+                dir.file_name()
+                    .and_then(|name| name.to_str())
+                    .map(String::from)
+            })
+            .context("could not figure out the grammar's name. Specify one with --name.")?;
+
+        Ok(dir_name
+            .trim_start_matches("tree-sitter-")
+            .to_ascii_lowercase())
+    }
+}
+
+/// Respect `$CC` and `$CXX` like `make` and the `cc` crate do.
+fn compiler(cxx: bool) -> OsString {
+    if cxx {
+        env::var_os("CXX").unwrap_or_else(|| OsString::from("c++"))
+    } else {
+        env::var_os("CC").unwrap_or_else(|| OsString::from("cc"))
+    }
+}
+
+fn run(mut command: Command) -> Result<()> {
+    let output = command
+        .output()
+        .with_context(|| format!("could not run {:?}. Is a C compiler installed?", command))?;
+
+    if !output.status.success() {
+        bail!(
+            "{:?} failed with {}:\n{}",
+            command,
+            output.status,
+            String::from_utf8_lossy(&output.stderr)
+        )
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn builder(source_dir: &str, name: Option<&str>) -> GrammarBuilder {
+        GrammarBuilder::new(
+            PathBuf::from(source_dir),
+            name.map(String::from),
+            env::temp_dir(),
+        )
+    }
+
+    #[test]
+    fn name_from_flag() {
+        assert_eq!(
+            builder("fixtures/grammars/tree-sitter-named", Some("Flagged"))
+                .name()
+                .unwrap(),
+            "flagged"
+        );
+    }
+
+    #[test]
+    fn name_from_grammar_json() {
+        assert_eq!(
+            builder("fixtures/grammars/tree-sitter-named", None)
+                .name()
+                .unwrap(),
+            "renamed"
+        );
+    }
+
+    #[test]
+    fn name_from_directory() {
+        assert_eq!(
+            builder("fixtures/grammars/tree-sitter-Unnamed", None)
+                .name()
+                .unwrap(),
+            "unnamed"
+        );
+    }
+
+    #[test]
+    fn no_name_at_all() {
+        assert_eq!(
+            builder("fixtures/grammars/does-not-exist", None)
+                .name()
+                .unwrap_err()
+                .to_string(),
+            "could not figure out the grammar's name. Specify one with --name."
+        );
+    }
+
+    #[test]
+    fn needs_a_generated_parser() {
+        assert_eq!(
+            builder("fixtures/grammars/tree-sitter-Unnamed", None)
+                .build()
+                .unwrap_err()
+                .to_string(),
+            "fixtures/grammars/tree-sitter-Unnamed doesn't look like a tree-sitter grammar: there's no src/parser.c. If the grammar lives in a subdirectory (like tree-sitter-typescript/tsx), point me there instead. If there's only a grammar.js, run `tree-sitter generate` first."
+        );
+    }
+}
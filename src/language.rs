@@ -1,69 +1,542 @@
-use anyhow::{Context, Result};
-use libloading::{Symbol, Library};
+use crate::query_diagnostic::QueryDiagnostic;
+use anyhow::{bail, Context, Result};
+use libloading::{Library, Symbol};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::c_void;
+use std::fmt::Write;
+use std::fs;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use tree_sitter::Query;
 
 #[cfg(all(unix, not(target_os = "macos")))]
-const DYLIB_EXTENSION: &str = "so";
+pub const DYLIB_EXTENSION: &str = "so";
 
 #[cfg(windows)]
-const DYLIB_EXTENSION: &str = "dll";
+pub const DYLIB_EXTENSION: &str = "dll";
 
 #[cfg(all(unix, target_os = "macos"))]
-const DYLIB_EXTENSION: &str = "dylib";
+pub const DYLIB_EXTENSION: &str = "dylib";
+
+type LanguageFn = fn() -> tree_sitter::Language;
+
+/// Grammars compiled into the binary with the `bundled-grammars` feature.
+/// Libraries on the search path with the same name take precedence.
+#[cfg(feature = "bundled-grammars")]
+const BUNDLED: &[(&str, LanguageFn)] = &[
+    ("c", tree_sitter_c::language),
+    ("cpp", tree_sitter_cpp::language),
+    ("go", tree_sitter_go::language),
+    ("java", tree_sitter_java::language),
+    ("javascript", tree_sitter_javascript::language),
+    ("python", tree_sitter_python::language),
+    ("rust", tree_sitter_rust::language),
+    ("toml", tree_sitter_toml::language),
+    ("tsx", tree_sitter_typescript::language_tsx),
+    ("typescript", tree_sitter_typescript::language_typescript),
+];
+
+#[cfg(not(feature = "bundled-grammars"))]
+const BUNDLED: &[(&str, LanguageFn)] = &[];
+
+/// Injection queries that come with the bundled grammars. Files on the query
+/// search path take precedence over these.
+#[cfg(feature = "bundled-grammars")]
+const BUNDLED_INJECTIONS: &[(&str, &str)] = &[
+    ("javascript", tree_sitter_javascript::INJECTION_QUERY),
+    ("rust", tree_sitter_rust::INJECTIONS_QUERY),
+];
+
+#[cfg(not(feature = "bundled-grammars"))]
+const BUNDLED_INJECTIONS: &[(&str, &str)] = &[];
+
+/// The `node-types.json` that comes with each bundled grammar, describing
+/// which children each kind of node can have.
+#[cfg(feature = "bundled-grammars")]
+const BUNDLED_NODE_TYPES: &[(&str, &str)] = &[
+    ("c", tree_sitter_c::NODE_TYPES),
+    ("cpp", tree_sitter_cpp::NODE_TYPES),
+    ("go", tree_sitter_go::NODE_TYPES),
+    ("java", tree_sitter_java::NODE_TYPES),
+    ("javascript", tree_sitter_javascript::NODE_TYPES),
+    ("python", tree_sitter_python::NODE_TYPES),
+    ("rust", tree_sitter_rust::NODE_TYPES),
+    ("toml", tree_sitter_toml::NODE_TYPES),
+    ("tsx", tree_sitter_typescript::TSX_NODE_TYPES),
+    ("typescript", tree_sitter_typescript::TYPESCRIPT_NODE_TYPES),
+];
+
+#[cfg(not(feature = "bundled-grammars"))]
+const BUNDLED_NODE_TYPES: &[(&str, &str)] = &[];
+
+/// Other names people commonly use for languages. Grammars are always
+/// named for the language in full, so these point at those names.
+const BUILTIN_ALIASES: &[(&str, &str)] = &[
+    ("c#", "c_sharp"),
+    ("c++", "cpp"),
+    ("cs", "c_sharp"),
+    ("csharp", "c_sharp"),
+    ("cxx", "cpp"),
+    ("ex", "elixir"),
+    ("exs", "elixir"),
+    ("golang", "go"),
+    ("hs", "haskell"),
+    ("js", "javascript"),
+    ("jsx", "javascript"),
+    ("kt", "kotlin"),
+    ("md", "markdown"),
+    ("py", "python"),
+    ("python3", "python"),
+    ("rb", "ruby"),
+    ("rs", "rust"),
+    ("sh", "bash"),
+    ("shell", "bash"),
+    ("ts", "typescript"),
+    ("yml", "yaml"),
+];
+
+/// Maps the names people type to the names grammars are loaded under. Users
+/// can add their own (or override ours) in the config file.
+#[derive(Debug, Clone)]
+pub struct Aliases(HashMap<String, String>);
+
+impl Aliases {
+    pub fn new() -> Aliases {
+        Aliases(
+            BUILTIN_ALIASES
+                .iter()
+                .map(|(alias, language)| (alias.to_string(), language.to_string()))
+                .collect(),
+        )
+    }
+
+    pub fn add(&mut self, alias: &str, language: &str) {
+        self.0.insert(normalize(alias), normalize(language));
+    }
+
+    /// Get the name of the grammar `name` refers to. Names that aren't
+    /// aliases are returned as-is (modulo case and whitespace.)
+    pub fn resolve(&self, name: &str) -> String {
+        let name = normalize(name);
+        match self.0.get(&name) {
+            Some(language) => language.clone(),
+            None => name,
+        }
+    }
+
+    /// Every alias for `language`, sorted.
+    pub fn aliases_for(&self, language: &str) -> Vec<String> {
+        let mut out: Vec<String> = self
+            .0
+            .iter()
+            .filter(|(_, target)| *target == language)
+            .map(|(alias, _)| alias.clone())
+            .collect();
+        out.sort();
+        out
+    }
+}
+
+impl Default for Aliases {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn normalize(name: &str) -> String {
+    name.trim().to_ascii_lowercase()
+}
+
+/// A handle to a grammar, either bundled or loaded from a library. Cloning
+/// one is cheap and shares the underlying grammar (and library.)
+#[derive(Debug, Clone)]
+pub struct Language(Arc<Grammar>);
 
 #[derive(Debug)]
-pub struct Language {
+struct Grammar {
     inner: tree_sitter::Language,
     name: String,
+    /// Where the grammar was loaded from, or `None` if it's bundled.
+    library_path: Option<PathBuf>,
+    /// `inner` points into this, so we have to keep it loaded for as long as
+    /// anyone has a handle to the grammar.
+    _library: Option<Library>,
+}
+
+/// Every grammar library this process has opened, keyed by path. Opening a
+/// library is relatively expensive and the grammar inside never changes, so
+/// everyone who asks for the same library shares one `Language`.
+fn loaded_libraries() -> &'static Mutex<HashMap<PathBuf, Language>> {
+    static LOADED: OnceLock<Mutex<HashMap<PathBuf, Language>>> = OnceLock::new();
+    LOADED.get_or_init(Default::default)
+}
+
+/// Finds grammars on a search path (or bundled into the binary) and hands
+/// out shared handles to them. Each name is only looked up once.
+#[derive(Debug)]
+pub struct Registry {
+    search_path: Vec<PathBuf>,
+    query_path: Vec<PathBuf>,
+    aliases: Aliases,
+    by_name: Mutex<HashMap<String, Language>>,
+}
+
+impl Registry {
+    pub fn new(search_path: Vec<PathBuf>, query_path: Vec<PathBuf>, aliases: Aliases) -> Registry {
+        Registry {
+            search_path,
+            query_path,
+            aliases,
+            by_name: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn search_path(&self) -> &[PathBuf] {
+        &self.search_path
+    }
+
+    pub fn query_path(&self) -> &[PathBuf] {
+        &self.query_path
+    }
+
+    pub fn aliases(&self) -> &Aliases {
+        &self.aliases
+    }
+
+    /// Get the grammar called `name` (or that `name` is an alias for) from
+    /// the first directory on the search path that has a library for it,
+    /// falling back to the bundled grammars if none do.
+    pub fn get(&self, name: &str) -> Result<Language> {
+        let name = self.aliases.resolve(name);
+
+        if let Some(language) = self.by_name.lock().unwrap().get(&name) {
+            return Ok(language.clone());
+        }
+
+        let language = self.find(&name)?;
+        self.by_name.lock().unwrap().insert(name, language.clone());
+
+        Ok(language)
+    }
+
+    fn find(&self, name: &str) -> Result<Language> {
+        if let Some(library_path) = self.find_library(name) {
+            return Language::load_library(&library_path, name);
+        }
+
+        match Language::bundled(name) {
+            Some(language) => Ok(language),
+            None => {
+                let mut message = format!(
+                    "could not find a grammar for `{}`. I looked for {}.{} in ",
+                    name, name, DYLIB_EXTENSION
+                );
+
+                if self.search_path.is_empty() {
+                    message.push_str("no directories at all, since none were configured.");
+                } else {
+                    message.push_str("these directories:\n");
+                    for dir in &self.search_path {
+                        // writing to a String can't fail
+                        let _ = writeln!(message, "  - {}", dir.display());
+                    }
+                }
+
+                message.push_str("Use --grammar-dir, $TREE_GREPPER_GRAMMAR_PATH, or `grammar-dirs` in the config file to add more.");
+
+                bail!(message)
+            }
+        }
+    }
+
+    /// Find the query file called `name` (like `injections`) for a language
+    /// on the query search path.
+    pub fn find_query(&self, language: &Language, name: &str) -> Option<PathBuf> {
+        self.query_path
+            .iter()
+            .map(|dir| dir.join(language.name()).join(format!("{}.scm", name)))
+            .find(|candidate| candidate.is_file())
+    }
+
+    /// The names of every query in the library for a language, sorted.
+    pub fn query_names(&self, language: &Language) -> BTreeSet<String> {
+        self.query_path
+            .iter()
+            .filter_map(|dir| fs::read_dir(dir.join(language.name())).ok())
+            .flatten()
+            .filter_map(|entry| {
+                let path = entry.ok()?.path();
+                if path.extension()? == "scm" {
+                    path.file_stem()?.to_str().map(String::from)
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Get the source of the injection query for a language: the first
+    /// `injections.scm` on the query search path, or the one that came with a
+    /// bundled grammar.
+    pub fn injections_source(&self, language: &Language) -> Result<Option<String>> {
+        if let Some(path) = self.find_query(language, "injections") {
+            return fs::read_to_string(&path)
+                .map(Some)
+                .with_context(|| format!("could not read {}", path.display()));
+        }
+
+        Ok(BUNDLED_INJECTIONS
+            .iter()
+            .find(|(name, _)| *name == language.name())
+            .map(|(_, source)| source.to_string()))
+    }
+
+    fn find_library(&self, name: &str) -> Option<PathBuf> {
+        self.search_path
+            .iter()
+            .map(|dir| dir.join(format!("{}.{}", name, DYLIB_EXTENSION)))
+            .find(|candidate| candidate.is_file())
+    }
+
+    /// Find every grammar on the search path or bundled into the binary,
+    /// keyed by language name. The value is the library the grammar would be
+    /// loaded from, or `None` for bundled grammars. When a name appears in
+    /// more than one place, only the one `get` would load is returned.
+    pub fn available(&self) -> BTreeMap<String, Option<PathBuf>> {
+        let mut out = BTreeMap::new();
+
+        for dir in &self.search_path {
+            // directories on the default search path usually don't exist, so
+            // we don't treat that as an error.
+            let entries = match fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.extension().and_then(|ext| ext.to_str()) != Some(DYLIB_EXTENSION)
+                    || !path.is_file()
+                {
+                    continue;
+                }
+
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    out.entry(name.to_ascii_lowercase()).or_insert(Some(path));
+                }
+            }
+        }
+
+        for (name, _) in BUNDLED {
+            out.entry(name.to_string()).or_insert(None);
+        }
+
+        out
+    }
 }
 
 impl Language {
-    pub fn get_language(runtime_path: &std::path::Path, name: &str) -> Result<Self> {
-        let name = name.to_ascii_lowercase();
-        let mut library_path = runtime_path.join(&name);
-        library_path.set_extension(DYLIB_EXTENSION);
+    fn bundled(name: &str) -> Option<Language> {
+        BUNDLED
+            .iter()
+            .find(|(bundled_name, _)| *bundled_name == name)
+            .map(|(bundled_name, language_fn)| {
+                Language(Arc::new(Grammar {
+                    inner: language_fn(),
+                    name: bundled_name.to_string(),
+                    library_path: None,
+                    _library: None,
+                }))
+            })
+    }
+
+    fn load_library(library_path: &Path, name: &str) -> Result<Self> {
+        let mut loaded = loaded_libraries().lock().unwrap();
+        if let Some(language) = loaded.get(library_path) {
+            return Ok(language.clone());
+        }
 
-        let library = unsafe { Library::new(&library_path) }
-            .with_context(|| format!("Error opening dynamic library {:?}", &library_path))?;
+        let library = unsafe { Library::new(library_path) }
+            .with_context(|| format!("Error opening dynamic library {:?}", library_path))?;
         let language_fn_name = format!("tree_sitter_{}", name.replace('-', "_"));
         let language = unsafe {
-            let language_fn: Symbol<unsafe extern "C" fn() -> tree_sitter::Language> = library
+            let language_fn: Symbol<unsafe extern "C" fn() -> *const c_void> = library
                 .get(language_fn_name.as_bytes())
                 .with_context(|| format!("Failed to load symbol {}", language_fn_name))?;
-            language_fn()
+            let raw = language_fn();
+            if raw.is_null() {
+                bail!(
+                    "{} returned a null language from {}",
+                    library_path.display(),
+                    language_fn_name
+                )
+            }
+
+            // tree_sitter::Language is a transparent wrapper around this
+            // pointer, so this is the same thing the library's own Rust
+            // bindings would do.
+            std::mem::transmute::<*const c_void, tree_sitter::Language>(raw)
         };
-        std::mem::forget(library);
-        Ok(Self {
-            name,
+
+        // We check this here instead of waiting for `Parser::set_language` to
+        // fail because by then we've lost track of where the grammar came
+        // from, and the error just says "incompatible language version."
+        Self::check_abi_version(language.version(), library_path)?;
+
+        let language = Language(Arc::new(Grammar {
             inner: language,
-        })
+            name: name.to_string(),
+            library_path: Some(library_path.to_owned()),
+            _library: Some(library),
+        }));
+        loaded.insert(library_path.to_owned(), language.clone());
+
+        Ok(language)
     }
 
-    pub fn name(&self) -> &str {
-        &self.name
-    }
-
-    pub fn name_for_types_builder(&self) -> &'static str {
-        match self.name.as_str() {
-            "c" => "c",
-            "cpp" => "cpp",
-            "elixir" => "elixir",
-            "elm" => "elm",
-            "go" => "go",
-            "haskell" => "haskell",
-            "java" => "java",
-            "javascript" => "js",
-            "markdown" => "markdown",
-            "nix" => "nix",
-            "php" => "php",
-            "python" => "py",
-            "ruby" => "ruby",
-            "rust" => "rust",
-            "typescript" => "ts",
-            _ => panic!("Unknown language: {}", self.name),
+    /// The range of grammar ABI versions the linked tree-sitter runtime can use.
+    pub fn supported_abi_versions() -> RangeInclusive<usize> {
+        tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION..=tree_sitter::LANGUAGE_VERSION
+    }
+
+    fn check_abi_version(version: usize, library_path: &Path) -> Result<()> {
+        let supported = Self::supported_abi_versions();
+        if supported.contains(&version) {
+            return Ok(());
         }
+
+        let advice = if version > *supported.end() {
+            "It was generated by a newer tree-sitter CLI than this version of tree-grepper understands. Try upgrading tree-grepper, or regenerating the grammar with an older tree-sitter CLI."
+        } else {
+            "It was generated by an older tree-sitter CLI than this version of tree-grepper understands. Try regenerating it with `tree-sitter generate` and rebuilding it with `tree-grepper grammar build`."
+        };
+
+        bail!(
+            "{} uses tree-sitter ABI version {}, but tree-grepper can only load versions {} through {}. {}",
+            library_path.display(),
+            version,
+            supported.start(),
+            supported.end(),
+            advice
+        )
+    }
+
+    pub fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    pub fn library_path(&self) -> Option<&Path> {
+        self.0.library_path.as_deref()
+    }
+
+    /// The tree-sitter ABI version the grammar was generated with.
+    pub fn abi_version(&self) -> usize {
+        self.0.inner.version()
+    }
+
+    pub fn node_kind_count(&self) -> usize {
+        self.0.inner.node_kind_count()
     }
 
     pub fn ts_lang(&self) -> tree_sitter::Language {
-        self.inner
+        self.0.inner
+    }
+
+    /// Where this grammar's `node-types.json` is: `NAME.node-types.json`
+    /// next to the library. Bundled grammars don't have one on disk.
+    pub fn node_types_path(&self) -> Option<PathBuf> {
+        let library_path = self.library_path()?;
+        let path = library_path.with_file_name(format!("{}.node-types.json", self.name()));
+
+        if path.is_file() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
+    /// Get the source of this grammar's `node-types.json`, if we can find
+    /// it. Grammars loaded from a library never use the bundled copy, since
+    /// the library may be a different version.
+    pub fn node_types_source(&self) -> Result<Option<String>> {
+        if let Some(path) = self.node_types_path() {
+            return fs::read_to_string(&path)
+                .map(Some)
+                .with_context(|| format!("could not read {}", path.display()));
+        }
+
+        if self.library_path().is_some() {
+            return Ok(None);
+        }
+
+        Ok(BUNDLED_NODE_TYPES
+            .iter()
+            .find(|(name, _)| *name == self.name())
+            .map(|(_, source)| source.to_string()))
+    }
+
+    /// Compile a query for this language. If it doesn't compile, the error
+    /// points out where the problem is and suggests fixes.
+    pub fn query(&self, source: &str) -> Result<Query> {
+        Query::new(self.0.inner, source)
+            .map_err(|error| QueryDiagnostic::new(self, source, error).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn grammars_are_only_loaded_once() {
+        let registry = Registry::new(Vec::new(), Vec::new(), Aliases::new());
+
+        let rust = registry.get("rust").unwrap();
+        let rs = registry.get("rs").unwrap();
+
+        assert!(Arc::ptr_eq(&rust.0, &rs.0));
+    }
+
+    #[test]
+    fn supported_abi_versions_load() {
+        for version in Language::supported_abi_versions() {
+            assert!(Language::check_abi_version(version, Path::new("x.so")).is_ok());
+        }
+    }
+
+    #[test]
+    fn newer_abi_versions_are_an_error() {
+        let newer = Language::supported_abi_versions().end() + 1;
+
+        assert_eq!(
+            Language::check_abi_version(newer, Path::new("grammars/new.so"))
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "grammars/new.so uses tree-sitter ABI version {}, but tree-grepper can only load versions {} through {}. It was generated by a newer tree-sitter CLI than this version of tree-grepper understands. Try upgrading tree-grepper, or regenerating the grammar with an older tree-sitter CLI.",
+                newer,
+                tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION,
+                tree_sitter::LANGUAGE_VERSION,
+            )
+        );
+    }
+
+    #[test]
+    fn older_abi_versions_are_an_error() {
+        let older = Language::supported_abi_versions().start() - 1;
+
+        assert_eq!(
+            Language::check_abi_version(older, Path::new("grammars/old.so"))
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "grammars/old.so uses tree-sitter ABI version {}, but tree-grepper can only load versions {} through {}. It was generated by an older tree-sitter CLI than this version of tree-grepper understands. Try regenerating it with `tree-sitter generate` and rebuilding it with `tree-grepper grammar build`.",
+                older,
+                tree_sitter::MIN_COMPATIBLE_LANGUAGE_VERSION,
+                tree_sitter::LANGUAGE_VERSION,
+            )
+        );
     }
 }
@@ -0,0 +1,464 @@
+use crate::language::Aliases;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// How much of the start and end of a file we look at for shebangs and
+/// modelines. Vim only checks the first and last five lines, so this is
+/// plenty.
+const SNIFF_BYTES: u64 = 1024;
+
+/// Interpreters whose name isn't the name (or an alias) of their grammar.
+/// Anything else in a shebang (`python3`, `ruby`, `bash`...) is resolved as
+/// an alias.
+const INTERPRETERS: &[(&str, &str)] = &[
+    ("bun", "javascript"),
+    ("dash", "bash"),
+    ("deno", "typescript"),
+    ("escript", "erlang"),
+    ("ksh", "bash"),
+    ("node", "javascript"),
+    ("nodejs", "javascript"),
+    ("pypy", "python"),
+    ("rscript", "r"),
+    ("runghc", "haskell"),
+    ("runhaskell", "haskell"),
+    ("ts-node", "typescript"),
+    ("zsh", "bash"),
+];
+
+/// Figures out what language a file is written in when its path doesn't
+/// tell us. In order, we check:
+///
+/// 1. `linguist-language` in `.gitattributes` (the same overrides GitHub
+///    uses)
+/// 2. the shebang (`#!/usr/bin/env python3`)
+/// 3. editor modelines (`vim: ft=python` or `-*- mode: python -*-`)
+///
+/// Everything we find is resolved as an alias, so the result is a grammar
+/// name.
+pub struct LanguageDetector<'aliases> {
+    aliases: &'aliases Aliases,
+    git_attributes: GitAttributes,
+}
+
+impl<'aliases> LanguageDetector<'aliases> {
+    pub fn new(aliases: &'aliases Aliases) -> LanguageDetector<'aliases> {
+        LanguageDetector {
+            aliases,
+            git_attributes: GitAttributes::new(),
+        }
+    }
+
+    pub fn detect(&self, path: &Path) -> Option<String> {
+        if let Some(language) = self.detect_from_git_attributes(path) {
+            return Some(language);
+        }
+
+        let (head, tail) = sniff(path)?;
+
+        let first = head.lines().next().unwrap_or_default();
+        if let Some(interpreter) = from_shebang(first) {
+            return Some(self.resolve_interpreter(&interpreter));
+        }
+
+        // Emacs only looks at the first line (or the second, if the first
+        // is a shebang, which we've already ruled out.) Vim looks at the
+        // first and last few lines.
+        let vim_lines = head
+            .lines()
+            .take(5)
+            .chain(tail.as_deref().unwrap_or(&head).lines().rev().take(5));
+        std::iter::once(first)
+            .filter_map(from_emacs_modeline)
+            .chain(vim_lines.filter_map(from_vim_modeline))
+            .next()
+            .map(|language| self.aliases.resolve(&language))
+    }
+
+    /// Only check `.gitattributes`, without opening the file. This is for
+    /// files whose path already says what they are, where a shebang or
+    /// modeline shouldn't override it but GitHub's overrides should.
+    pub fn detect_from_git_attributes(&self, path: &Path) -> Option<String> {
+        self.git_attributes
+            .language_for(path)
+            .map(|language| self.aliases.resolve(&language))
+    }
+
+    fn resolve_interpreter(&self, interpreter: &str) -> String {
+        match INTERPRETERS.iter().find(|(name, _)| *name == interpreter) {
+            Some((_, language)) => language.to_string(),
+            None => self.aliases.resolve(interpreter),
+        }
+    }
+}
+
+/// Read the start and end of a file as text. The end is `None` if the
+/// start already covers the whole file. Returns `None` for files we can't
+/// read or that look binary.
+fn sniff(path: &Path) -> Option<(String, Option<String>)> {
+    let mut file = File::open(path).ok()?;
+
+    let mut head = Vec::with_capacity(SNIFF_BYTES as usize);
+    (&mut file).take(SNIFF_BYTES).read_to_end(&mut head).ok()?;
+    if head.contains(&0) {
+        return None;
+    }
+
+    let len = file.metadata().ok()?.len();
+    let tail = if len > SNIFF_BYTES {
+        let mut tail = Vec::with_capacity(SNIFF_BYTES as usize);
+        file.seek(SeekFrom::Start(
+            len.saturating_sub(SNIFF_BYTES).max(SNIFF_BYTES),
+        ))
+        .ok()?;
+        file.read_to_end(&mut tail).ok()?;
+        Some(String::from_utf8_lossy(&tail).into_owned())
+    } else {
+        None
+    };
+
+    Some((String::from_utf8_lossy(&head).into_owned(), tail))
+}
+
+/// Get the interpreter from a shebang line, without any path or version:
+/// `#!/usr/bin/env -S python3.11 -u` gives `python`.
+fn from_shebang(line: &str) -> Option<String> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+
+    let mut interpreter = words.next()?.rsplit('/').next()?;
+    if interpreter == "env" {
+        // skip env's own flags (like `-S`) and any variables it sets
+        interpreter = words.find(|word| !word.starts_with('-') && !word.contains('='))?;
+    }
+
+    let name = interpreter
+        .trim_end_matches(|c: char| c.is_ascii_digit() || c == '.')
+        .to_ascii_lowercase();
+
+    if name.is_empty() {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// Get the mode from an Emacs file variables line, either
+/// `-*- mode: python -*-` or the short form `-*- python -*-`.
+fn from_emacs_modeline(line: &str) -> Option<String> {
+    let (_, rest) = line.split_once("-*-")?;
+    let (vars, _) = rest.split_once("-*-")?;
+
+    if !vars.contains(':') {
+        return non_empty(vars);
+    }
+
+    vars.split(';').find_map(|var| {
+        let (name, value) = var.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("mode") {
+            non_empty(value)
+        } else {
+            None
+        }
+    })
+}
+
+/// Get the filetype from a Vim modeline, like `vim: ft=python` or
+/// `# vim: set filetype=python ts=4 :`.
+fn from_vim_modeline(line: &str) -> Option<String> {
+    // the marker has to start a word, otherwise `index: a=b` would count
+    let (_, options) = ["vim:", "vi:", "ex:"].iter().find_map(|marker| {
+        line.match_indices(marker).find_map(|(start, _)| {
+            let starts_word = line[..start]
+                .chars()
+                .next_back()
+                .map(char::is_whitespace)
+                .unwrap_or(true);
+            if starts_word {
+                Some(line.split_at(start + marker.len()))
+            } else {
+                None
+            }
+        })
+    })?;
+
+    let options = options.trim_start();
+    let options = options
+        .strip_prefix("set ")
+        .or_else(|| options.strip_prefix("se "))
+        .unwrap_or(options);
+
+    options
+        .split(|c: char| c.is_whitespace() || c == ':')
+        .find_map(|option| {
+            let (name, value) = option.split_once('=')?;
+            match name {
+                "ft" | "filetype" | "syn" | "syntax" => non_empty(value),
+                _ => None,
+            }
+        })
+}
+
+fn non_empty(value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+/// One line of a `.gitattributes` file that says something about
+/// `linguist-language`. `language` is `None` when the line unsets it.
+struct Rule {
+    matcher: Gitignore,
+    language: Option<String>,
+}
+
+/// `linguist-language` settings from `.gitattributes` files, cached by
+/// directory since every file in a directory shares them.
+struct GitAttributes {
+    by_dir: Mutex<HashMap<PathBuf, Arc<Vec<Rule>>>>,
+}
+
+impl GitAttributes {
+    fn new() -> GitAttributes {
+        GitAttributes {
+            by_dir: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Deeper `.gitattributes` files win over shallower ones, and later
+    /// lines win over earlier ones, same as git. We stop looking at the
+    /// root of the repository.
+    fn language_for(&self, path: &Path) -> Option<String> {
+        let path = path.canonicalize().ok()?;
+
+        for dir in path.ancestors().skip(1) {
+            let rules = self.rules_in(dir);
+            if let Some(rule) = rules
+                .iter()
+                .rev()
+                .find(|rule| rule.matcher.matched(&path, false).is_ignore())
+            {
+                return rule.language.clone();
+            }
+
+            if dir.join(".git").exists() {
+                break;
+            }
+        }
+
+        None
+    }
+
+    fn rules_in(&self, dir: &Path) -> Arc<Vec<Rule>> {
+        if let Some(rules) = self.by_dir.lock().unwrap().get(dir) {
+            return rules.clone();
+        }
+
+        let rules = Arc::new(
+            fs::read_to_string(dir.join(".gitattributes"))
+                .map(|contents| parse_git_attributes(dir, &contents))
+                .unwrap_or_default(),
+        );
+
+        self.by_dir
+            .lock()
+            .unwrap()
+            .insert(dir.to_path_buf(), rules.clone());
+
+        rules
+    }
+}
+
+fn parse_git_attributes(dir: &Path, contents: &str) -> Vec<Rule> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            let pattern = words.next()?;
+
+            // git doesn't allow negative patterns here, and we don't try to
+            // handle quoted ones.
+            if pattern.starts_with('#') || pattern.starts_with('!') || pattern.starts_with('"') {
+                return None;
+            }
+
+            let language = words.rev().find_map(|attr| {
+                if let Some(value) = attr.strip_prefix("linguist-language=") {
+                    Some(Some(value.to_string()))
+                } else if attr == "-linguist-language" || attr == "!linguist-language" {
+                    Some(None)
+                } else {
+                    None
+                }
+            })?;
+
+            let mut builder = GitignoreBuilder::new(dir);
+            builder.add_line(None, pattern).ok()?;
+
+            Some(Rule {
+                matcher: builder.build().ok()?,
+                language,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shebang_with_path() {
+        assert_eq!(from_shebang("#!/bin/bash"), Some("bash".to_string()));
+    }
+
+    #[test]
+    fn shebang_through_env() {
+        assert_eq!(
+            from_shebang("#!/usr/bin/env ruby"),
+            Some("ruby".to_string())
+        );
+    }
+
+    #[test]
+    fn shebang_skips_env_flags_and_variables() {
+        assert_eq!(
+            from_shebang("#!/usr/bin/env -S python3 -u"),
+            Some("python".to_string())
+        );
+        assert_eq!(
+            from_shebang("#!/usr/bin/env -S LC_ALL=C node"),
+            Some("node".to_string())
+        );
+    }
+
+    #[test]
+    fn shebang_drops_versions() {
+        assert_eq!(
+            from_shebang("#!/usr/bin/python3.11"),
+            Some("python".to_string())
+        );
+    }
+
+    #[test]
+    fn not_a_shebang() {
+        assert_eq!(from_shebang("# !/bin/bash"), None);
+        assert_eq!(from_shebang("#!/usr/bin/env"), None);
+    }
+
+    #[test]
+    fn vim_modeline_with_set() {
+        assert_eq!(
+            from_vim_modeline("// vim: set ft=rust:"),
+            Some("rust".to_string())
+        );
+    }
+
+    #[test]
+    fn vim_modeline_without_set() {
+        assert_eq!(
+            from_vim_modeline("# vi: ts=4 ft=python"),
+            Some("python".to_string())
+        );
+        assert_eq!(
+            from_vim_modeline("# vim: filetype=bash"),
+            Some("bash".to_string())
+        );
+    }
+
+    #[test]
+    fn vim_marker_has_to_start_a_word() {
+        assert_eq!(from_vim_modeline("index: ft=python"), None);
+    }
+
+    #[test]
+    fn vim_modeline_without_filetype() {
+        assert_eq!(from_vim_modeline("# vim: set ts=4 sw=4:"), None);
+    }
+
+    #[test]
+    fn emacs_modeline_with_mode() {
+        assert_eq!(
+            from_emacs_modeline("# -*- mode: ruby -*-"),
+            Some("ruby".to_string())
+        );
+        assert_eq!(
+            from_emacs_modeline("# -*- coding: utf-8; mode: ruby -*-"),
+            Some("ruby".to_string())
+        );
+    }
+
+    #[test]
+    fn emacs_modeline_short_form() {
+        assert_eq!(
+            from_emacs_modeline("# -*- Ruby -*-"),
+            Some("Ruby".to_string())
+        );
+    }
+
+    #[test]
+    fn emacs_modeline_without_mode() {
+        assert_eq!(from_emacs_modeline("# -*- coding: utf-8 -*-"), None);
+        assert_eq!(from_emacs_modeline("# -*- mode: ruby"), None);
+    }
+
+    /// The language the last matching rule gives `file`, like
+    /// `GitAttributes::language_for` does for a single `.gitattributes`.
+    fn git_attributes_language(contents: &str, file: &str) -> Option<String> {
+        let dir = Path::new("/repo");
+        parse_git_attributes(dir, contents)
+            .iter()
+            .rev()
+            .find(|rule| rule.matcher.matched(dir.join(file), false).is_ignore())
+            .and_then(|rule| rule.language.clone())
+    }
+
+    #[test]
+    fn git_attributes_set_language() {
+        let contents = "*.tmpl linguist-language=HTML\n";
+
+        assert_eq!(
+            git_attributes_language(contents, "page.tmpl"),
+            Some("HTML".to_string())
+        );
+        assert_eq!(git_attributes_language(contents, "page.txt"), None);
+    }
+
+    #[test]
+    fn git_attributes_unset_language() {
+        for unset in ["-linguist-language", "!linguist-language"] {
+            let contents = format!("*.tmpl linguist-language=HTML\nvendor/*.tmpl {}\n", unset);
+
+            assert_eq!(git_attributes_language(&contents, "vendor/page.tmpl"), None);
+            assert_eq!(
+                git_attributes_language(&contents, "page.tmpl"),
+                Some("HTML".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn git_attributes_last_attribute_wins() {
+        assert_eq!(
+            git_attributes_language(
+                "*.tmpl linguist-language=HTML -linguist-language linguist-language=Go\n",
+                "page.tmpl"
+            ),
+            Some("Go".to_string())
+        );
+    }
+
+    #[test]
+    fn git_attributes_skip_other_lines() {
+        let contents =
+            "# *.tmpl linguist-language=HTML\n!*.tmpl linguist-language=HTML\n*.tmpl text\n";
+
+        assert!(parse_git_attributes(Path::new("/repo"), contents).is_empty());
+    }
+}
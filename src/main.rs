@@ -1,28 +1,58 @@
 mod cli;
+mod config;
 mod extractor;
 mod extractor_chooser;
+mod file_types;
+mod grammar_builder;
 mod language;
+mod language_detection;
+mod node_types;
+mod pattern;
+mod query_diagnostic;
+mod query_lint;
+mod scope;
 mod tree_view;
 
 use anyhow::{bail, Context, Result};
 use bat::line_range::LineRange;
 use bat::line_range::LineRanges;
-use cli::{Invocation, QueryFormat, QueryOpts, TreeOpts};
+use cli::{
+    CheckQueriesOpts, Invocation, LanguagesOpts, NodeTypesOpts, QueryFormat, QueryOpts, TreeOpts,
+};
 use crossbeam::channel;
+use extractor::{ExtractedFile, Extractor};
+use extractor_chooser::ExtractorChooser;
+use grammar_builder::GrammarBuilder;
 use itertools::Itertools;
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use rayon::iter::{IntoParallelRefIterator, ParallelBridge, ParallelIterator};
+use serde::Serialize;
 use std::env;
 use std::fs;
 use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
 use tree_sitter::Parser;
 
 #[global_allocator]
 static ALLOCATOR: bump_alloc::BumpAlloc = bump_alloc::BumpAlloc::new();
 
+/// Like grep, we exit with 0 when something matched, 1 when nothing did,
+/// and 2 when there was a problem.
+const EXIT_NO_MATCHES: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
 fn main() {
     let mut buffer = BufWriter::new(io::stdout());
 
-    if let Err(error) = try_main(env::args().collect(), &mut buffer) {
+    let result = try_main(env::args().collect(), &mut buffer);
+
+    if let Err(error) = result {
+        // whatever we managed to write before the error should still come
+        // out (like the problems --check-query found), since exiting
+        // skips flushing the buffer.
+        let _ = buffer.flush();
+
         if let Some(err) = error.downcast_ref::<io::Error>() {
             // a broken pipe is totally normal and fine. It's what we get when
             // we pipe to something like `head` that only takes a certain number
@@ -34,37 +64,236 @@ fn main() {
 
         if let Some(clap_error) = error.downcast_ref::<clap::Error>() {
             // Clap errors (--help or misuse) are already well-formatted,
-            // so we don't have to do any additional work.
-            eprint!("{}", clap_error);
-        } else {
-            eprintln!("{:?}", error);
+            // and clap knows which ones are really errors, so it can print
+            // them and pick the exit status itself.
+            clap_error.exit();
         }
 
-        std::process::exit(1);
+        eprintln!("{:?}", error);
+        std::process::exit(EXIT_ERROR);
     }
 
     buffer.flush().expect("failed to flush buffer!");
+
+    if let Ok(false) = result {
+        std::process::exit(EXIT_NO_MATCHES);
+    }
 }
 
-fn try_main(args: Vec<String>, out: impl Write) -> Result<()> {
+/// Returns whether we found anything, for the exit status. Everything
+/// besides searching always counts as finding something.
+fn try_main(args: Vec<String>, out: impl Write) -> Result<bool> {
     let invocation = Invocation::from_args(args)
         .context("couldn't get a valid configuration from the command-line options")?;
 
     match invocation {
         Invocation::DoQuery(query_opts) => {
-            do_query(query_opts, out).context("couldn't perform the query")
+            return do_query(query_opts, out).context("couldn't perform the query");
         }
-        Invocation::ShowLanguages => {
-            show_languages(out).context("couldn't show the list of languages")
+        Invocation::ShowLanguages(languages_opts) => {
+            show_languages(languages_opts, out).context("couldn't show the list of languages")
         }
         Invocation::ShowTree(tree_opts) => {
             show_tree(tree_opts, out).context("couldn't show the tree")
         }
+        Invocation::ShowNodeTypes(node_types_opts) => {
+            show_node_types(node_types_opts, out).context("couldn't show the node types")
+        }
+        Invocation::CheckQueries(check_opts) => {
+            check_queries(check_opts, out).context("couldn't check the queries")
+        }
+        Invocation::BuildGrammar(builder) => {
+            build_grammar(builder, out).context("couldn't build the grammar")
+        }
+    }?;
+
+    Ok(true)
+}
+
+#[derive(Debug, Serialize)]
+struct LanguageInfo {
+    name: String,
+    /// `None` for grammars bundled into the binary
+    path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    abi_version: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    node_kind_count: Option<usize>,
+    aliases: Vec<String>,
+    file_types: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn show_languages(opts: LanguagesOpts, mut out: impl Write) -> Result<()> {
+    let infos: Vec<LanguageInfo> = opts
+        .registry
+        .available()
+        .into_iter()
+        .map(|(name, path)| match opts.registry.get(&name) {
+            Ok(language) => LanguageInfo {
+                abi_version: Some(language.abi_version()),
+                node_kind_count: Some(language.node_kind_count()),
+                aliases: opts.registry.aliases().aliases_for(language.name()),
+                file_types: opts.file_types.globs_for(language.name()),
+                error: None,
+                path: language.library_path().map(|path| path.to_owned()),
+                name,
+            },
+            // one broken library shouldn't stop us from listing the rest,
+            // so we report the problem alongside the others.
+            Err(err) => LanguageInfo {
+                abi_version: None,
+                node_kind_count: None,
+                aliases: opts.registry.aliases().aliases_for(&name),
+                file_types: Vec::new(),
+                error: Some(format!("{:#}", err)),
+                name,
+                path,
+            },
+        })
+        .collect();
+
+    match opts.format {
+        QueryFormat::Json => {
+            serde_json::to_writer(&mut out, &infos).context("could not write JSON output")?;
+        }
+
+        QueryFormat::PrettyJson => {
+            serde_json::to_writer_pretty(&mut out, &infos)
+                .context("could not write JSON output")?;
+        }
+
+        QueryFormat::JsonLines => {
+            for info in &infos {
+                writeln!(
+                    out,
+                    "{}",
+                    serde_json::to_string(info).context("could not write JSON output")?
+                )
+                .context("could not write line")?;
+            }
+        }
+
+        QueryFormat::Lines | QueryFormat::Pretty => {
+            if infos.is_empty() {
+                writeln!(
+                    out,
+                    "I couldn't find any grammars. I looked in:\n{}",
+                    opts.registry
+                        .search_path()
+                        .iter()
+                        .map(|dir| format!("  - {}", dir.display()))
+                        .join("\n")
+                )
+                .context("could not write line")?;
+                return Ok(());
+            }
+
+            let name_width = infos.iter().map(|info| info.name.len()).max().unwrap_or(0);
+
+            for info in &infos {
+                let source = match &info.path {
+                    Some(path) => path.display().to_string(),
+                    None => "(bundled)".to_string(),
+                };
+
+                match &info.error {
+                    Some(error) => writeln!(
+                        out,
+                        "{:name_width$}  {}  error: {}",
+                        info.name, source, error,
+                    ),
+                    None => writeln!(
+                        out,
+                        "{:name_width$}  {}  ABI {}, {} node kinds, file types: {}{}",
+                        info.name,
+                        source,
+                        info.abi_version.unwrap_or_default(),
+                        info.node_kind_count.unwrap_or_default(),
+                        if info.file_types.is_empty() {
+                            "unknown".to_string()
+                        } else {
+                            info.file_types.join(" ")
+                        },
+                        if info.aliases.is_empty() {
+                            String::new()
+                        } else {
+                            format!(", aliases: {}", info.aliases.join(" "))
+                        },
+                    ),
+                }
+                .context("could not write line")?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn build_grammar(builder: GrammarBuilder, mut out: impl Write) -> Result<()> {
+    let language = builder.build()?;
+
+    writeln!(
+        out,
+        "built {} (ABI {}) at {}",
+        language.name(),
+        language.abi_version(),
+        language
+            .library_path()
+            .map(|path| path.display().to_string())
+            .unwrap_or_default(),
+    )
+    .context("could not write line")
+}
+
+fn check_queries(opts: CheckQueriesOpts, mut out: impl Write) -> Result<()> {
+    let mut linter = query_lint::Linter::new().with_shown_captures(opts.shown_captures);
+    let mut problems = Vec::new();
+
+    for query in &opts.queries {
+        let label = query.label.as_deref().unwrap_or_default();
+
+        // the linter only understands queries that compile, and if one
+        // doesn't, the error already says what's wrong.
+        query
+            .language
+            .query(&query.source)
+            .with_context(|| format!("query {} didn't compile", label))?;
+
+        let (found, note) = linter.check(&query.language, label, &query.source)?;
+        if let Some(note) = note {
+            writeln!(out, "note: {}\n", note).context("could not write line")?;
+        }
+        problems.extend(found);
+    }
+
+    for problem in &problems {
+        writeln!(out, "{}\n", problem).context("could not write line")?;
+    }
+
+    match problems.len() {
+        0 => writeln!(out, "no problems found").context("could not write line"),
+        1 => bail!("found a problem in the queries"),
+        count => bail!("found {} problems in the queries", count),
     }
 }
 
-fn show_languages(_out: impl Write) -> Result<()> {
-    // TODO
+fn show_node_types(opts: NodeTypesOpts, mut out: impl Write) -> Result<()> {
+    let node_types = node_types::NodeTypes::new(&opts.language)?;
+
+    match opts.format {
+        QueryFormat::Json | QueryFormat::JsonLines => {
+            serde_json::to_writer(&mut out, &node_types).context("could not write JSON output")?;
+        }
+
+        QueryFormat::PrettyJson => {
+            serde_json::to_writer_pretty(&mut out, &node_types)
+                .context("could not write JSON output")?;
+        }
+
+        QueryFormat::Lines | QueryFormat::Pretty => node_types.write_text(out)?,
+    }
 
     Ok(())
 }
@@ -84,66 +313,125 @@ fn show_tree(opts: TreeOpts, out: impl Write) -> Result<()> {
     tree_view::tree_view(&tree, source.as_bytes(), out)
 }
 
-fn do_query(opts: QueryOpts, mut out: impl Write) -> Result<()> {
-    // You might think "why not use ParallelBridge here?" Well, the quick answer
-    // is that I benchmarked it and having things separated here and handling
-    // their own errors actually speeds up this part of the code by like 20%!
-    let items: Vec<ignore::DirEntry> =
-        find_files(&opts).context("had a problem while walking the filesystem")?;
-
+/// Returns whether anything matched.
+fn do_query(opts: QueryOpts, mut out: impl Write) -> Result<bool> {
     let chooser = opts
         .extractor_chooser()
         .context("couldn't construct a filetype matcher")?;
 
-    let mut extracted_files = items
-        .par_iter()
-        .filter_map(|entry| {
-            chooser
-                .extractor_for(entry)
-                .map(|extractor| (entry, extractor))
-        })
-        .map_init(Parser::new, |parser, (entry, extractor)| {
-            extractor
-                .extract_from_file(entry.path(), parser)
-                .with_context(|| {
-                    format!("could not extract matches from {}", entry.path().display())
-                })
-        })
-        .filter_map(|result_containing_option| match result_containing_option {
-            Ok(None) => None,
-            Ok(Some(extraction)) => Some(Ok(extraction)),
-            Err(err) => Some(Err(err)),
-        })
-        .collect::<Result<Vec<extractor::ExtractedFile>>>()
-        .context("couldn't extract matches from files")?;
+    // injection queries say what language to use however the grammar's
+    // authors felt like writing it, so we resolve aliases here.
+    let injected = |language: &str| chooser.extractor_for_language(&opts.aliases.resolve(language));
+
+    // how many files or directories we couldn't search, but kept going
+    // without
+    let problems = AtomicUsize::new(0);
+
+    let mut extracted_files = match opts.max_results {
+        Some(max_results) => extract_until(&opts, &chooser, &injected, max_results, &problems)?,
+        None => extract_all(&opts, &chooser, &injected, &problems)?,
+    };
 
     if opts.sort {
         extracted_files.sort()
     }
 
+    // files are searched in parallel, so we can find a few more matches
+    // than we need before everything stops.
+    if let Some(max_results) = opts.max_results {
+        let mut remaining = max_results;
+        extracted_files.retain_mut(|extracted_file| {
+            extracted_file.truncate(remaining);
+            remaining -= extracted_file.match_count();
+            extracted_file.match_count() > 0
+        });
+    }
+
+    let found = !extracted_files.is_empty();
+
+    // a problem with some file means we might have missed matches, so
+    // whether we found any doesn't tell the whole story. Unless we were
+    // asked to keep going, we report it as an error once we're done.
+    let problems = problems.into_inner();
+    let finish = || {
+        if problems == 0 || opts.keep_going {
+            Ok(found)
+        } else {
+            bail!(
+                "couldn't search {} {}, as reported above. Use --keep-going to ignore problems like this.",
+                problems,
+                if problems == 1 { "path" } else { "paths" }
+            )
+        }
+    };
+
+    // like grep, --quiet only asks whether anything matched, so finding a
+    // match is a success even if we couldn't search everywhere.
+    if opts.quiet {
+        return if found { Ok(true) } else { finish() };
+    }
+
+    if opts.show_count {
+        for extracted_file in extracted_files {
+            let count = if opts.group {
+                extracted_file.match_count()
+            } else {
+                extracted_file.matches.len()
+            };
+            match extracted_file.file {
+                Some(path) if !opts.no_filename => {
+                    writeln!(out, "{}:{}", path.display(), count)
+                }
+                _ => writeln!(out, "{}", count),
+            }
+            .context("could not write count")?;
+        }
+
+        return finish();
+    }
+
     match opts.format {
         QueryFormat::Lines => {
             for extracted_file in extracted_files {
-                write!(out, "{}", extracted_file).context("could not write lines")?;
+                match (opts.group, opts.show_query) {
+                    (true, true) => write!(out, "{:#}", extracted_file.grouped()),
+                    (true, false) => write!(out, "{}", extracted_file.grouped()),
+                    (false, true) => write!(out, "{:#}", extracted_file),
+                    (false, false) => write!(out, "{}", extracted_file),
+                }
+                .context("could not write lines")?;
             }
         }
 
+        QueryFormat::Json if opts.group => {
+            let grouped: Vec<extractor::GroupedFile> =
+                extracted_files.iter().map(|file| file.grouped()).collect();
+            serde_json::to_writer(out, &grouped).context("could not write JSON output")?;
+        }
+
         QueryFormat::Json => {
             serde_json::to_writer(out, &extracted_files).context("could not write JSON output")?;
         }
 
         QueryFormat::JsonLines => {
             for extracted_file in extracted_files {
-                writeln!(
-                    out,
-                    "{}",
+                let line = if opts.group {
+                    serde_json::to_string(&extracted_file.grouped())
+                } else {
                     serde_json::to_string(&extracted_file)
-                        .context("could not write JSON output")?
-                )
-                .context("could not write line")?;
+                };
+
+                writeln!(out, "{}", line.context("could not write JSON output")?)
+                    .context("could not write line")?;
             }
         }
 
+        QueryFormat::PrettyJson if opts.group => {
+            let grouped: Vec<extractor::GroupedFile> =
+                extracted_files.iter().map(|file| file.grouped()).collect();
+            serde_json::to_writer_pretty(out, &grouped).context("could not write JSON output")?;
+        }
+
         QueryFormat::PrettyJson => {
             serde_json::to_writer_pretty(out, &extracted_files)
                 .context("could not write JSON output")?;
@@ -163,12 +451,22 @@ fn do_query(opts: QueryOpts, mut out: impl Write) -> Result<()> {
                         (m.start.row + 1, end)
                     })
                     .collect::<Vec<_>>();
+                let path = file.file.as_ref().unwrap();
+                let mut input = bat::Input::from_file(path);
+                if opts.show_query {
+                    input = input.title(format!(
+                        "{} ({})",
+                        path.display(),
+                        file.queries().join(", ")
+                    ));
+                }
+
                 let mut pp = bat::PrettyPrinter::new();
-                pp.input_file(file.file.unwrap())
-                    .header(true)
+                pp.input(input)
+                    .header(!opts.no_filename)
                     .snip(true)
                     .grid(true)
-                    .line_numbers(true)
+                    .line_numbers(!opts.no_line_number)
                     .use_italics(true)
                     .tab_width(Some(opts.tab_width))
                     // .term_width(console::Term::stdout().size().1 as usize)
@@ -190,10 +488,141 @@ fn do_query(opts: QueryOpts, mut out: impl Write) -> Result<()> {
         }
     }
 
-    Ok(())
+    finish()
+}
+
+fn extract_all<'a>(
+    opts: &QueryOpts,
+    chooser: &ExtractorChooser<'a>,
+    injected: &(dyn Fn(&str) -> Option<&'a Extractor> + Sync),
+    problems: &AtomicUsize,
+) -> Result<Vec<ExtractedFile<'a>>> {
+    // You might think "why not use ParallelBridge here?" Well, the quick answer
+    // is that I benchmarked it and having things separated here and handling
+    // their own errors actually speeds up this part of the code by like 20%!
+    let items: Vec<ignore::DirEntry> =
+        find_files(opts, problems).context("had a problem while walking the filesystem")?;
+
+    items
+        .par_iter()
+        .filter_map(|entry| {
+            chooser
+                .extractor_for(entry)
+                .map(|extractor| (entry, extractor))
+        })
+        .map_init(Parser::new, |parser, (entry, extractor)| {
+            extract_file(entry, extractor, parser, injected)
+        })
+        .filter_map(|result| keep_going(opts, problems, result))
+        .collect::<Result<Vec<ExtractedFile>>>()
+        .context("couldn't extract matches from files")
+}
+
+/// Like `extract_all`, but we stop once we've found `max_results` matches.
+/// That means searching files while we're still walking the filesystem
+/// (which is slower when we have to look at everything, but means we can
+/// stop walking early), and skipping whatever files are left once we have
+/// enough.
+fn extract_until<'a>(
+    opts: &QueryOpts,
+    chooser: &ExtractorChooser<'a>,
+    injected: &(dyn Fn(&str) -> Option<&'a Extractor> + Sync),
+    max_results: usize,
+    problems: &AtomicUsize,
+) -> Result<Vec<ExtractedFile<'a>>> {
+    let found = AtomicUsize::new(0);
+    let stop = AtomicBool::new(max_results == 0);
+    let (sender, receiver) = channel::unbounded();
+
+    thread::scope(|scope| {
+        let walker = scope.spawn(|| walk_files(opts, sender, &stop, problems));
+
+        let extracted = receiver
+            .into_iter()
+            .par_bridge()
+            .filter(|_| !stop.load(Ordering::Relaxed))
+            .filter_map(|entry| {
+                chooser
+                    .extractor_for(&entry)
+                    .map(|extractor| (entry, extractor))
+            })
+            .map_init(Parser::new, |parser, (entry, extractor)| {
+                let result = extract_file(&entry, extractor, parser, injected);
+
+                if let Ok(Some(extracted_file)) = &result {
+                    let so_far = found.fetch_add(extracted_file.match_count(), Ordering::Relaxed)
+                        + extracted_file.match_count();
+                    if so_far >= max_results {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                }
+
+                result
+            })
+            .filter_map(|result| keep_going(opts, problems, result))
+            // an error is going to end the search anyway
+            .inspect(|result| {
+                if result.is_err() {
+                    stop.store(true, Ordering::Relaxed);
+                }
+            })
+            .collect::<Result<Vec<ExtractedFile>>>()
+            .context("couldn't extract matches from files");
+
+        walker
+            .join()
+            .expect("the thread walking the filesystem panicked")
+            .context("had a problem while walking the filesystem")?;
+
+        extracted
+    })
+}
+
+fn extract_file<'a>(
+    entry: &ignore::DirEntry,
+    extractor: &'a Extractor,
+    parser: &mut Parser,
+    injected: &(dyn Fn(&str) -> Option<&'a Extractor> + Sync),
+) -> Result<Option<ExtractedFile<'a>>> {
+    extractor
+        .extract_from_file(entry.path(), parser, injected)
+        .with_context(|| format!("could not extract matches from {}", entry.path().display()))
+}
+
+/// With --keep-going, report a problem with a file and move on to the next
+/// instead of stopping.
+fn keep_going<'a>(
+    opts: &QueryOpts,
+    problems: &AtomicUsize,
+    result: Result<Option<ExtractedFile<'a>>>,
+) -> Option<Result<ExtractedFile<'a>>> {
+    match result {
+        Err(err) if opts.keep_going => {
+            eprintln!("{:#}", err);
+            problems.fetch_add(1, Ordering::Relaxed);
+            None
+        }
+        _ => result.transpose(),
+    }
 }
 
-fn find_files(opts: &QueryOpts) -> Result<Vec<ignore::DirEntry>> {
+fn find_files(opts: &QueryOpts, problems: &AtomicUsize) -> Result<Vec<ignore::DirEntry>> {
+    let (sender, receiver) = channel::unbounded();
+
+    walk_files(opts, sender, &AtomicBool::new(false), problems)?;
+
+    Ok(receiver.iter().collect())
+}
+
+/// Send every file under `opts.paths` to `root_sender`, until `stop` is set.
+/// Paths we can't walk (like ones that don't exist) are reported and
+/// counted in `problems`, but don't stop the walk.
+fn walk_files(
+    opts: &QueryOpts,
+    root_sender: channel::Sender<ignore::DirEntry>,
+    stop: &AtomicBool,
+    problems: &AtomicUsize,
+) -> Result<()> {
     let mut builder = match opts.paths.split_first() {
         Some((first, rest)) => {
             let mut builder = ignore::WalkBuilder::new(first);
@@ -206,8 +635,6 @@ fn find_files(opts: &QueryOpts) -> Result<Vec<ignore::DirEntry>> {
         None => bail!("I need at least one file or directory to walk!"),
     };
 
-    let (root_sender, receiver) = channel::unbounded();
-
     builder
         .git_ignore(opts.git_ignore)
         .git_exclude(opts.git_ignore)
@@ -216,6 +643,7 @@ fn find_files(opts: &QueryOpts) -> Result<Vec<ignore::DirEntry>> {
         .run(|| {
             let sender = root_sender.clone();
             Box::new(move |entry_result| match entry_result {
+                _ if stop.load(Ordering::Relaxed) => ignore::WalkState::Quit,
                 Ok(entry) => match sender.send(entry) {
                     Ok(()) => ignore::WalkState::Continue,
                     Err(e) => {
@@ -225,6 +653,7 @@ fn find_files(opts: &QueryOpts) -> Result<Vec<ignore::DirEntry>> {
                 },
                 Err(e) => {
                     eprintln!("{}", e);
+                    problems.fetch_add(1, Ordering::Relaxed);
                     ignore::WalkState::Continue
                 }
             })
@@ -232,13 +661,43 @@ fn find_files(opts: &QueryOpts) -> Result<Vec<ignore::DirEntry>> {
 
     drop(root_sender);
 
-    Ok(receiver.iter().collect())
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn predicates_on_underscore_captures() {
+        let calls = |method: &str| {
+            call(&[
+                "tree-grepper",
+                "-q",
+                "rust",
+                &format!(
+                    "((call_expression function: (field_expression field: (field_identifier) @_method)) @call (#eq? @_method \"{}\"))",
+                    method
+                ),
+                "-q",
+                "rust",
+                &format!(
+                    "((identifier) @_name (#match? @_name \"^{}$\")) @name",
+                    method
+                ),
+                "-f",
+                "lines",
+                "--sort",
+                "fixtures/rust",
+            ])
+        };
+
+        assert_eq!(calls("no_such_method"), "");
+        insta::assert_snapshot!(calls("unwrap"));
+    }
+
+    #[cfg(feature = "bundled-grammars")]
     fn call(args: &[&str]) -> String {
         let mut bytes = Vec::new();
         try_main(
@@ -250,36 +709,53 @@ mod tests {
         String::from_utf8(bytes).unwrap()
     }
 
+    /// Run with `args` and say whether we found anything. `main` turns an
+    /// error that isn't from clap into exit status 2.
+    fn run(args: &[&str]) -> Result<bool> {
+        try_main(args.iter().map(|s| s.to_string()).collect(), Vec::new())
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    fn exits_with_error(result: Result<bool>) -> bool {
+        match result {
+            Ok(_) => false,
+            Err(err) => err.downcast_ref::<clap::Error>().is_none(),
+        }
+    }
+
+    #[cfg(feature = "bundled-grammars")]
     #[test]
     fn lines_output() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "elm",
-            "(import_clause)",
+            "rust",
+            "(line_comment)",
             "-f",
             "lines",
             "--sort",
             "--no-gitignore",
-            "vendor/tree-sitter-elm/examples",
+            "fixtures/rust",
         ]))
     }
 
+    #[cfg(feature = "bundled-grammars")]
     #[test]
     fn json_output() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "elm",
-            "(import_clause)",
+            "rust",
+            "(line_comment)",
             "-f",
             "json",
             "--sort",
             "--no-gitignore",
-            "vendor/tree-sitter-elm/examples",
+            "fixtures/rust",
         ]))
     }
 
+    #[cfg(feature = "bundled-grammars")]
     #[test]
     fn json_lines_output() {
         insta::assert_snapshot!(call(&[
@@ -290,189 +766,851 @@ mod tests {
             "-f",
             "json-lines",
             "--sort",
-            "vendor/tree-sitter-javascript/examples"
+            "fixtures/all/javascript"
         ]))
     }
 
+    #[cfg(feature = "bundled-grammars")]
     #[test]
     fn pretty_json_output() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "elm",
-            "(import_clause)",
+            "rust",
+            "(line_comment)",
             "--format=pretty-json",
             "--sort",
             "--no-gitignore",
-            "vendor/tree-sitter-elm/examples",
+            "fixtures/rust",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn bundled_grammar() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "-q",
+            "rust",
+            "(function_item name: (identifier)@name)",
+            "-f",
+            "lines",
+            "--sort",
+            "fixtures/rust",
         ]))
     }
 
-    // All languages should have a test that just spits out their entire node
-    // tree. We use this to know about changes in the vendored parsers!
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn language_alias() {
+        let query = "(function_item name: (identifier)@name)";
+
+        assert_eq!(
+            call(&[
+                "tree-grepper",
+                "-q",
+                "rs",
+                query,
+                "-f",
+                "lines",
+                "--sort",
+                "fixtures/rust"
+            ]),
+            call(&[
+                "tree-grepper",
+                "-q",
+                "rust",
+                query,
+                "-f",
+                "lines",
+                "--sort",
+                "fixtures/rust"
+            ]),
+        )
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn positional_query() {
+        let query = "(function_item name: (identifier)@name)";
 
+        assert_eq!(
+            call(&[
+                "tree-grepper",
+                "rust",
+                query,
+                "fixtures/rust",
+                "-f",
+                "lines",
+                "--sort",
+            ]),
+            call(&[
+                "tree-grepper",
+                "-q",
+                "rust",
+                query,
+                "-f",
+                "lines",
+                "--sort",
+                "fixtures/rust"
+            ]),
+        )
+    }
+
+    #[cfg(feature = "bundled-grammars")]
     #[test]
-    fn all_cpp() {
+    fn injections() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "cpp",
-            "(_)",
-            "--format=pretty-json",
+            "rust",
+            "(call_expression function: (field_expression field: (field_identifier) @method))",
+            "--injections",
+            "rust",
+            "-f",
+            "lines",
             "--sort",
-            "--no-gitignore",
-            "vendor/tree-sitter-cpp/examples",
+            "fixtures/rust",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn language_detection() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "-q",
+            "python",
+            "(call function: (identifier) @fn)",
+            "-f",
+            "lines",
+            "--sort",
+            "fixtures/detection",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn language_detection_skips_known_file_types() {
+        // script.py has a Rust modeline, but it's a .py file, so it's Python
+        assert_eq!(
+            call(&[
+                "tree-grepper",
+                "-q",
+                "rust",
+                "(identifier) @id",
+                "-f",
+                "lines",
+                "fixtures/detection-known",
+            ]),
+            ""
+        )
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn type_add() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "-q",
+            "rust",
+            "(function_item name: (identifier) @name)",
+            "--type-add",
+            "rust:*.rs.in",
+            "-f",
+            "lines",
+            "fixtures/type-add",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn general_predicates() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "-q",
+            "rust",
+            "((line_comment) @note (#lua-match? @note \"^// %u+:\") (#not-has-ancestor? @note mod_item))",
+            "-q",
+            "rust",
+            "((identifier) @counter (#any-of? @counter \"count\" \"counts\") (#has-parent? @counter let_declaration))",
+            "-f",
+            "lines",
+            "--sort",
+            "fixtures/rust",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn query_file() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "--query-file",
+            "rust",
+            "fixtures/queries/rust/unwraps.scm",
+            "-q",
+            "rust",
+            "(line_comment)",
+            "-f",
+            "lines",
+            "--sort",
+            "fixtures/rust",
         ]))
     }
 
+    #[cfg(feature = "bundled-grammars")]
     #[test]
-    fn all_elm() {
+    fn query_labels() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "elm",
+            "rust",
+            "(line_comment)",
+            "--label",
+            "comments",
+            "--query-file",
+            "rust",
+            "fixtures/queries/rust/unwraps.scm",
+            "-q",
+            "rust",
+            "(macro_invocation macro: (identifier) @macro)",
+            "-f",
+            "lines",
+            "--sort",
+            "fixtures/rust",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn query_diagnostic() {
+        let error = try_main(
+            [
+                "tree-grepper",
+                "rust",
+                "(function_item nme: (identifer))",
+                "fixtures/rust",
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+            Box::new(Vec::new()),
+        )
+        .unwrap_err();
+
+        insta::assert_snapshot!(error.root_cause().to_string())
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn check_query() {
+        let mut bytes = Vec::new();
+        let error = try_main(
+            [
+                "tree-grepper",
+                "--check-query",
+                "--query-file",
+                "rust",
+                "fixtures/check-query/mistakes.scm",
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+            Box::new(&mut bytes),
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            error.root_cause().to_string(),
+            "found 5 problems in the queries"
+        );
+        insta::assert_snapshot!(String::from_utf8(bytes).unwrap())
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn grouped_lines() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "rust",
+            "(function_item name: (identifier) @name parameters: (parameters (parameter)* @param))",
+            "--group",
+            "-f",
+            "lines",
+            "--sort",
+            "fixtures/rust",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn grouped_json() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "rust",
+            "(call_expression function: (_) @function arguments: (arguments (_) @argument))",
+            "--group",
+            "-f",
+            "pretty-json",
+            "--sort",
+            "fixtures/rust",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn capture() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "rust",
+            "(call_expression function: (identifier) @_fn arguments: (arguments) @args)",
+            "--capture",
+            "_fn",
+            "-f",
+            "lines",
+            "--sort",
+            "fixtures/rust",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn exclude_capture() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "rust",
+            "(function_item name: (identifier) @name body: (block) @body)",
+            "--exclude-capture",
+            "body",
+            "-f",
+            "lines",
+            "--sort",
+            "fixtures/rust",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn pattern() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "--pattern",
+            "rust",
+            "$A.unwrap()",
+            "--pattern",
+            "rust",
+            "let $NAME = word_counts($$$);",
+            "--pattern",
+            "rust",
+            "HashMap::new()",
+            "--group",
+            "-f",
+            "lines",
+            "--sort",
+            "fixtures/rust",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn in_kind() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "--in",
+            "rust",
+            "comment",
+            "TODO|FIXME",
+            "--in",
+            "rust",
+            "(string_literal) @string",
+            "\\w+",
+            "-f",
+            "lines",
+            "--sort",
+            "fixtures/rust",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn inside_and_not_inside() {
+        let test_module =
+            "((attribute_item) @_attr . (mod_item) @module (#eq? @_attr \"#[cfg(test)]\"))";
+
+        insta::assert_snapshot!(
+            call(&[
+                "tree-grepper",
+                "-q",
+                "rust",
+                "(call_expression function: (identifier) @function)",
+                "--label",
+                "in-tests",
+                "--inside",
+                "rust",
+                test_module,
+                "-f",
+                "lines",
+                "fixtures/rust",
+            ]) + &call(&[
+                "tree-grepper",
+                "-q",
+                "rust",
+                "(call_expression function: (identifier) @function)",
+                "--label",
+                "outside-tests",
+                "--not-inside",
+                "rust",
+                test_module,
+                "-f",
+                "lines",
+                "fixtures/rust",
+            ])
+        )
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn require_and_forbid() {
+        let use_effect =
+            "(call_expression function: (identifier) @call (#eq? @call \"useEffect\"))";
+        let imports_react = "(import_clause (identifier) @name (#eq? @name \"React\"))";
+
+        insta::assert_snapshot!(
+            call(&[
+                "tree-grepper",
+                "-q",
+                "tsx",
+                use_effect,
+                "--label",
+                "require",
+                "--require",
+                "tsx",
+                imports_react,
+                "-f",
+                "lines",
+                "--sort",
+                "fixtures/require",
+            ]) + &call(&[
+                "tree-grepper",
+                "-q",
+                "tsx",
+                use_effect,
+                "--label",
+                "forbid",
+                "--forbid",
+                "tsx",
+                imports_react,
+                "-f",
+                "lines",
+                "--sort",
+                "fixtures/require",
+            ])
+        )
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn max_count() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "--in",
+            "rust",
+            "comment",
+            "[A-Z]+",
+            "--max-count",
+            "2",
+            "--group",
+            "-f",
+            "lines",
+            "fixtures/rust",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn max_count_skips_hidden_matches() {
+        // the comments come first, but they're hidden, so they shouldn't
+        // use up the limit
+        assert_eq!(
+            call(&[
+                "tree-grepper",
+                "-q",
+                "rust",
+                "(line_comment) @comment (function_item name: (identifier) @name)",
+                "--exclude-capture",
+                "comment",
+                "--max-count",
+                "2",
+                "-f",
+                "lines",
+                "fixtures/rust",
+            ]),
+            "fixtures/rust/lib.rs:4:8:name:word_counts\nfixtures/rust/lib.rs:13:8:name:most_common\n"
+        )
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn max_count_goes_by_position_with_injections() {
+        // `first()` is in code injected into the `println!`, so it's found
+        // after `second()` but comes before it in the file
+        assert_eq!(
+            call(&[
+                "tree-grepper",
+                "-q",
+                "rust",
+                "(call_expression function: (identifier) @call)",
+                "--injections",
+                "rust",
+                "--max-count",
+                "1",
+                "-f",
+                "lines",
+                "fixtures/injections",
+            ]),
+            "fixtures/injections/main.rs:2:20:call:first\n"
+        )
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn scopes_and_conditions_apply_to_injected_code() {
+        let calls = "(call function: (identifier) @call)";
+        let search = |extra: &[&str]| {
+            let mut args = vec!["tree-grepper", "-q", "python", calls];
+            args.extend(extra);
+            args.extend([
+                "--injections",
+                "javascript",
+                "-f",
+                "lines",
+                "fixtures/injections/app.js",
+            ]);
+            call(&args)
+        };
+
+        assert_eq!(
+            search(&[
+                "--inside",
+                "python",
+                "(function_definition name: (identifier) @_name (#eq? @_name \"main\")) @main",
+            ]),
+            "fixtures/injections/app.js:6:5:call:print\nfixtures/injections/app.js:6:11:call:helper\n"
+        );
+        assert_eq!(
+            search(&[
+                "--require",
+                "python",
+                "(function_definition name: (identifier) @name (#eq? @name \"missing\"))",
+            ]),
+            ""
+        );
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn max_results() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "rust",
+            "(function_item name: (identifier) @name parameters: (parameters) @params)",
+            "--max-results",
+            "2",
+            "--group",
+            "-f",
+            "lines",
+            "fixtures/rust",
+        ]))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn quiet() {
+        assert_eq!(
+            call(&[
+                "tree-grepper",
+                "rust",
+                "(function_item)",
+                "--quiet",
+                "fixtures/rust",
+            ]),
+            ""
+        )
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn found_matches() {
+        let found = |query: &str| {
+            run(&["tree-grepper", "rust", query, "--quiet", "fixtures/rust"]).unwrap()
+        };
+
+        assert!(found("(function_item)"));
+        assert!(!found("(macro_definition)"));
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn missing_paths_are_an_error() {
+        assert!(exits_with_error(run(&[
+            "tree-grepper",
+            "rust",
+            "(function_item)",
+            "fixtures/does-not-exist",
+        ])));
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn keep_going_past_missing_paths() {
+        assert!(run(&[
+            "tree-grepper",
+            "rust",
+            "(function_item)",
+            "--keep-going",
+            "fixtures/does-not-exist",
+            "fixtures/rust",
+        ])
+        .unwrap());
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn bad_queries_are_an_error() {
+        assert!(exits_with_error(run(&[
+            "tree-grepper",
+            "rust",
+            "(function_item",
+            "fixtures/rust",
+        ])));
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn quiet_succeeds_despite_problems_once_something_matched() {
+        assert!(run(&[
+            "tree-grepper",
+            "rust",
+            "(function_item)",
+            "--quiet",
+            "fixtures/rust",
+            "fixtures/does-not-exist",
+        ])
+        .unwrap());
+    }
+
+    /// Show the bundled grammars, ignoring whatever's installed on this
+    /// machine.
+    #[cfg(feature = "bundled-grammars")]
+    fn bundled_languages(format: QueryFormat) -> String {
+        let mut aliases = language::Aliases::new();
+        aliases.add("rusty", "rust");
+
+        let mut bytes = Vec::new();
+        show_languages(
+            LanguagesOpts {
+                registry: language::Registry::new(Vec::new(), Vec::new(), aliases),
+                file_types: file_types::FileTypes::new(),
+                format,
+            },
+            &mut bytes,
+        )
+        .unwrap();
+
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn languages_table() {
+        insta::assert_snapshot!(bundled_languages(QueryFormat::Lines))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn languages_json() {
+        insta::assert_snapshot!(bundled_languages(QueryFormat::PrettyJson))
+    }
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn node_types() {
+        insta::assert_snapshot!(call(&["tree-grepper", "--node-types", "toml"]))
+    }
+
+    #[test]
+    fn node_types_with_a_query() {
+        for query_args in [
+            &["--pattern", "rust", "$A.unwrap()"][..],
+            &["--inside", "rust", "(function_item) @function"],
+            &["--require", "rust", "(function_item)"],
+            &["--max-count", "1"],
+        ] {
+            let mut args = vec!["tree-grepper", "--node-types", "rust"];
+            args.extend(query_args);
+
+            match run(&args) {
+                Ok(_) => panic!("{:?} should conflict with --node-types", query_args),
+                Err(err) => assert_eq!(
+                    err.downcast_ref::<clap::Error>().map(|err| err.kind()),
+                    Some(clap::ErrorKind::ArgumentConflict)
+                ),
+            }
+        }
+    }
+
+    // All bundled languages should have a test that just spits out their
+    // entire node tree. We use this to know about changes in the bundled
+    // parsers!
+
+    #[cfg(feature = "bundled-grammars")]
+    #[test]
+    fn all_c() {
+        insta::assert_snapshot!(call(&[
+            "tree-grepper",
+            "-q",
+            "c",
             "(_)",
             "--format=pretty-json",
             "--sort",
             "--no-gitignore",
-            "vendor/tree-sitter-elm/examples",
+            "fixtures/all/c",
         ]))
     }
 
+    #[cfg(feature = "bundled-grammars")]
     #[test]
-    fn all_haskell() {
+    fn all_cpp() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "haskell",
+            "cpp",
             "(_)",
             "--format=pretty-json",
             "--sort",
             "--no-gitignore",
-            "vendor/tree-sitter-haskell",
+            "fixtures/all/cpp",
         ]))
     }
 
+    #[cfg(feature = "bundled-grammars")]
     #[test]
-    fn all_javascript() {
+    fn all_go() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "javascript",
+            "go",
             "(_)",
             "--format=pretty-json",
             "--sort",
             "--no-gitignore",
-            // note that this doesn't include the entire vendor
-            // directory. tree-sitter-javascript vendors a couple of libraries
-            // to test things and it makes this test run unacceptably long. I
-            // think the slowdown is due to the diffing step; the tree-grepper
-            // code completes in a reasonable amount of time.
-            "vendor/tree-sitter-javascript/test",
+            "fixtures/all/go",
         ]))
     }
 
+    #[cfg(feature = "bundled-grammars")]
     #[test]
-    fn all_markdown() {
+    fn all_java() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "markdown",
+            "java",
             "(_)",
             "--format=pretty-json",
             "--sort",
             "--no-gitignore",
-            "vendor/tree-sitter-markdown/README.md",
+            "fixtures/all/java",
         ]))
     }
 
+    #[cfg(feature = "bundled-grammars")]
     #[test]
-    fn all_nix() {
+    fn all_javascript() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "nix",
+            "javascript",
             "(_)",
             "--format=pretty-json",
             "--sort",
             "--no-gitignore",
-            "vendor/tree-sitter-nix/test/highlight/basic.nix",
+            "fixtures/all/javascript",
         ]))
     }
 
+    #[cfg(feature = "bundled-grammars")]
     #[test]
-    fn all_php() {
+    fn all_python() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "php",
+            "python",
             "(_)",
             "--format=pretty-json",
             "--sort",
             "--no-gitignore",
-            "vendor/tree-sitter-php/test/highlight",
+            "fixtures/all/python",
         ]))
     }
 
+    #[cfg(feature = "bundled-grammars")]
     #[test]
-    fn all_ruby() {
+    fn all_rust() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "ruby",
+            "rust",
             "(_)",
             "--format=pretty-json",
             "--sort",
             "--no-gitignore",
-            "vendor/tree-sitter-ruby",
+            "fixtures/all/rust",
         ]))
     }
 
+    #[cfg(feature = "bundled-grammars")]
     #[test]
-    fn all_rust() {
+    fn all_toml() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "rust",
+            "toml",
             "(_)",
             "--format=pretty-json",
             "--sort",
             "--no-gitignore",
-            "vendor/tree-sitter-rust/examples",
+            "fixtures/all/toml",
         ]))
     }
 
+    #[cfg(feature = "bundled-grammars")]
     #[test]
-    fn all_typescript() {
+    fn all_tsx() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "typescript",
+            "tsx",
             "(_)",
             "--format=pretty-json",
             "--sort",
             "--no-gitignore",
-            // similar to JavaScript, there is one particular test file in this
-            // grammar that's *huge*. It seems to be a comprehensive listing of
-            // all the typescript syntax, maybe? Regardless, it makes this test
-            // unacceptably slow, so we just look at one particular file. If
-            // we see uncaught regressions in this function, we probably will
-            // make our own test file with the things we care about.
-            "vendor/tree-sitter-typescript/typescript/test.ts",
+            "fixtures/all/tsx",
         ]))
     }
 
+    #[cfg(feature = "bundled-grammars")]
     #[test]
-    fn all_elixir() {
+    fn all_typescript() {
         insta::assert_snapshot!(call(&[
             "tree-grepper",
             "-q",
-            "elixir",
+            "typescript",
             "(_)",
             "--format=pretty-json",
             "--sort",
             "--no-gitignore",
-            "vendor/tree-sitter-elixir",
+            "fixtures/all/typescript",
         ]))
     }
 }
@@ -0,0 +1,283 @@
+use crate::language::Language;
+use anyhow::{Context, Result};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Write;
+
+/// Everything we know about the kinds of nodes a grammar produces. The
+/// kinds and fields come from the grammar itself, so we always have those.
+/// Supertypes and which children each kind allows only come from the
+/// grammar's `node-types.json`, so they're empty if we can't find it.
+#[derive(Debug, Serialize)]
+pub struct NodeTypes {
+    language: String,
+    /// Where the details came from: a path, "bundled", or `None` if we
+    /// couldn't find `node-types.json`.
+    node_types: Option<String>,
+    named: Vec<String>,
+    anonymous: Vec<String>,
+    /// Each field, with the kinds of nodes that have it
+    fields: BTreeMap<String, BTreeSet<String>>,
+    /// Each supertype (like `_expression`), with the kinds it stands for
+    supertypes: BTreeMap<String, Vec<TypeRef>>,
+    /// What each named kind can contain
+    kinds: BTreeMap<String, KindInfo>,
+}
+
+/// One entry in `node-types.json`. Supertypes have `subtypes`; other kinds
+/// may have `fields` and `children`.
+#[derive(Debug, Deserialize)]
+struct NodeTypeJson {
+    #[serde(rename = "type")]
+    kind: String,
+    named: bool,
+    #[serde(default)]
+    fields: BTreeMap<String, ChildTypes>,
+    children: Option<ChildTypes>,
+    subtypes: Option<Vec<TypeRef>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct KindInfo {
+    pub fields: BTreeMap<String, ChildTypes>,
+    /// Children that aren't in a field
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub children: Option<ChildTypes>,
+}
+
+impl KindInfo {
+    /// Everything that can match an unfielded child pattern, which is
+    /// every child in or out of a field, sorted and without duplicates.
+    pub fn all_child_types(&self) -> Vec<TypeRef> {
+        self.fields
+            .values()
+            .chain(self.children.iter())
+            .flat_map(|child_types| child_types.types.iter().cloned())
+            .sorted()
+            .dedup()
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ChildTypes {
+    pub multiple: bool,
+    pub required: bool,
+    pub types: Vec<TypeRef>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+pub struct TypeRef {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub named: bool,
+}
+
+impl NodeTypes {
+    pub fn new(language: &Language) -> Result<NodeTypes> {
+        let ts_lang = language.ts_lang();
+
+        let mut named = BTreeSet::new();
+        let mut anonymous = BTreeSet::new();
+        for id in 0..ts_lang.node_kind_count() as u16 {
+            // hidden kinds (like `_expression`) never show up in a tree
+            if !ts_lang.node_kind_is_visible(id) {
+                continue;
+            }
+
+            if let Some(kind) = ts_lang.node_kind_for_id(id) {
+                if ts_lang.node_kind_is_named(id) {
+                    named.insert(kind.to_string());
+                } else {
+                    anonymous.insert(kind.to_string());
+                }
+            }
+        }
+
+        // field IDs start at 1
+        let mut fields: BTreeMap<String, BTreeSet<String>> = (1..=ts_lang.field_count() as u16)
+            .filter_map(|id| ts_lang.field_name_for_id(id))
+            .map(|field| (field.to_string(), BTreeSet::new()))
+            .collect();
+
+        let mut out = NodeTypes {
+            language: language.name().to_string(),
+            node_types: None,
+            named: named.into_iter().collect(),
+            anonymous: anonymous.into_iter().collect(),
+            fields: BTreeMap::new(),
+            supertypes: BTreeMap::new(),
+            kinds: BTreeMap::new(),
+        };
+
+        if let Some(source) = language.node_types_source()? {
+            let entries: Vec<NodeTypeJson> = serde_json::from_str(&source)
+                .with_context(|| format!("could not read node-types.json for {}", out.language))?;
+
+            out.node_types = Some(match language.node_types_path() {
+                Some(path) => path.display().to_string(),
+                None => "bundled".to_string(),
+            });
+
+            for entry in entries.into_iter().filter(|entry| entry.named) {
+                if let Some(mut subtypes) = entry.subtypes {
+                    subtypes.sort();
+                    out.supertypes.insert(entry.kind, subtypes);
+                    continue;
+                }
+
+                for field in entry.fields.keys() {
+                    fields
+                        .entry(field.clone())
+                        .or_default()
+                        .insert(entry.kind.clone());
+                }
+
+                out.kinds.insert(
+                    entry.kind,
+                    KindInfo {
+                        fields: entry.fields,
+                        children: entry.children,
+                    },
+                );
+            }
+        }
+
+        out.fields = fields;
+
+        Ok(out)
+    }
+
+    pub fn has_details(&self) -> bool {
+        self.node_types.is_some()
+    }
+
+    pub fn language(&self) -> &str {
+        &self.language
+    }
+
+    pub fn kind(&self, kind: &str) -> Option<&KindInfo> {
+        self.kinds.get(kind)
+    }
+
+    /// `type_ref`, plus everything it stands for if it's a supertype (and
+    /// everything those stand for, and so on.)
+    pub fn expand(&self, type_ref: &TypeRef) -> BTreeSet<TypeRef> {
+        let mut out = BTreeSet::new();
+        let mut todo = vec![type_ref.clone()];
+
+        while let Some(next) = todo.pop() {
+            if let Some(subtypes) = self.supertypes.get(&next.kind).filter(|_| next.named) {
+                todo.extend(
+                    subtypes
+                        .iter()
+                        .filter(|subtype| !out.contains(*subtype))
+                        .cloned(),
+                );
+            }
+            out.insert(next);
+        }
+
+        out
+    }
+
+    /// Whether any kind of node can have a `type_ref` as a child. Kinds that
+    /// can't are either the root or extras (like comments), which can
+    /// appear anywhere.
+    pub fn is_ever_a_child(&self, type_ref: &TypeRef) -> bool {
+        self.kinds
+            .values()
+            .flat_map(|info| info.fields.values().chain(info.children.iter()))
+            .flat_map(|child_types| &child_types.types)
+            .any(|candidate| self.expand(candidate).contains(type_ref))
+    }
+
+    /// Write a human-readable listing of the grammar's vocabulary.
+    pub fn write_text(&self, mut out: impl Write) -> Result<()> {
+        if self.has_details() {
+            writeln!(
+                out,
+                "Fields and children are marked ? when they're optional, + when there can be more than one, and * for both.\n"
+            )?;
+        } else {
+            writeln!(
+                out,
+                "I couldn't find a node-types.json for {}, so I can only list kinds and fields, not supertypes or what each kind can contain. Put it next to the grammar library as {}.node-types.json to see those too.\n",
+                self.language, self.language
+            )?;
+        }
+
+        writeln!(out, "named kinds ({}):", self.named.len())?;
+        for kind in &self.named {
+            writeln!(out, "  {}", kind)?;
+
+            if let Some(info) = self.kinds.get(kind) {
+                for (field, types) in &info.fields {
+                    writeln!(
+                        out,
+                        "    {}{}: {}",
+                        field,
+                        quantifier(types),
+                        describe(&types.types)
+                    )?;
+                }
+
+                if let Some(children) = &info.children {
+                    writeln!(
+                        out,
+                        "    (children){}: {}",
+                        quantifier(children),
+                        describe(&children.types)
+                    )?;
+                }
+            }
+        }
+
+        if !self.supertypes.is_empty() {
+            writeln!(out, "\nsupertypes ({}):", self.supertypes.len())?;
+            for (supertype, subtypes) in &self.supertypes {
+                writeln!(out, "  {}: {}", supertype, describe(subtypes))?;
+            }
+        }
+
+        writeln!(out, "\nanonymous kinds ({}):", self.anonymous.len())?;
+        for kind in &self.anonymous {
+            writeln!(out, "  {:?}", kind)?;
+        }
+
+        writeln!(out, "\nfields ({}):", self.fields.len())?;
+        for (field, kinds) in &self.fields {
+            if kinds.is_empty() {
+                writeln!(out, "  {}", field)?;
+            } else {
+                writeln!(out, "  {}: on {}", field, kinds.iter().join(", "))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn quantifier(types: &ChildTypes) -> &'static str {
+    match (types.required, types.multiple) {
+        (true, false) => "",
+        (false, false) => "?",
+        (true, true) => "+",
+        (false, true) => "*",
+    }
+}
+
+/// `identifier | "self"`, quoting anonymous kinds the way queries do.
+pub fn describe(types: &[TypeRef]) -> String {
+    types
+        .iter()
+        .map(|type_ref| {
+            if type_ref.named {
+                type_ref.kind.clone()
+            } else {
+                format!("{:?}", type_ref.kind)
+            }
+        })
+        .join(" | ")
+}
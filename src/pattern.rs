@@ -0,0 +1,375 @@
+use crate::language::Language;
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use tree_sitter::{Node, Parser};
+
+/// What we replace metavariables with before parsing a pattern. These have
+/// to parse as an identifier in as many grammars as possible, so they're
+/// lowercase (in Haskell or Elm, uppercase would be a constructor) and
+/// don't have any punctuation besides underscores.
+const SINGLE_PREFIX: &str = "tgmetavar_";
+const MULTIPLE_PREFIX: &str = "tgmetavars_";
+
+/// Turn a code snippet with metavariables into a query that finds code
+/// shaped like it. The snippet is parsed with `language`'s grammar, so it
+/// has to be something the grammar can parse on its own (an expression or
+/// statement usually works.)
+///
+/// - `$NAME` matches any one node and captures it as `@NAME`. If the same
+///   metavariable appears more than once, every place it appears has to
+///   have the same text.
+/// - `$_` matches any one node without capturing it. So does any other
+///   name starting with an underscore (these are independent of each
+///   other, like in a query.)
+/// - `$$$` matches any number of nodes, including none. tree-sitter can't
+///   reliably capture a run of siblings, so these can't be captured:
+///   `$$$NAME` is an error, but `$$$_NAME` is fine.
+///
+/// Everything else has to match exactly: node kinds, fields, and the text
+/// of leaves. Whitespace and comments don't matter.
+pub fn to_query(language: &Language, pattern: &str) -> Result<String> {
+    let (code, metavariables) = replace_metavariables(pattern)?;
+
+    let mut parser = Parser::new();
+    parser
+        .set_language(language.ts_lang())
+        .context("could not set language")?;
+    let tree = parser.parse(&code, None).context(
+        "could not parse the pattern. This is an internal error and should be reported.",
+    )?;
+
+    // some grammars can't parse a lone expression (Rust only allows
+    // statements at the top level, for example) but recover by wrapping a
+    // perfectly good expression in an ERROR node. `innermost` looks through
+    // those, so we only have a problem if there's an error in what's left.
+    let root = innermost(tree.root_node(), &code);
+    if let Some(error) = find_error(root) {
+        bail!(
+            "I couldn't parse `{}` as {} (the problem starts at column {}.) A pattern has to be code the grammar understands on its own, like a whole expression or statement. Try `tree-grepper --show-tree {} FILE` on a file containing just the pattern to see what went wrong.",
+            pattern,
+            language.name(),
+            error.start_position().column + 1,
+            language.name(),
+        )
+    }
+
+    let mut builder = QueryBuilder {
+        source: code.as_bytes(),
+        metavariables: &metavariables,
+        seen: HashSet::new(),
+        predicates: Vec::new(),
+        next_internal: 0,
+        has_output: false,
+    };
+
+    let mut query = builder.node(root);
+    if !builder.has_output {
+        query.push_str(" @match");
+    }
+
+    if builder.predicates.is_empty() {
+        Ok(query)
+    } else {
+        Ok(format!("({} {})", query, builder.predicates.join(" ")))
+    }
+}
+
+enum Metavariable {
+    /// `$NAME`, or `None` for `$_` and friends
+    Single(Option<String>),
+    /// `$$$`, or `$$$_NAME`
+    Multiple,
+}
+
+/// Replace every metavariable with a placeholder identifier, and remember
+/// which is which.
+fn replace_metavariables(pattern: &str) -> Result<(String, HashMap<String, Metavariable>)> {
+    let mut code = String::with_capacity(pattern.len());
+    let mut metavariables = HashMap::new();
+    let mut rest = pattern;
+
+    while let Some(dollar) = rest.find('$') {
+        code.push_str(&rest[..dollar]);
+        rest = &rest[dollar..];
+
+        let multiple = rest.starts_with("$$$");
+        let after_dollars = if multiple { &rest[3..] } else { &rest[1..] };
+        let name_len = after_dollars
+            .find(|c: char| !(c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_'))
+            .unwrap_or(after_dollars.len());
+        let name = &after_dollars[..name_len];
+
+        // a `$` that isn't a metavariable (like in a PHP variable or a
+        // string) is just part of the code
+        if name.is_empty() && !multiple {
+            code.push('$');
+            rest = &rest[1..];
+            continue;
+        }
+
+        if multiple && !name.is_empty() && !name.starts_with('_') {
+            bail!(
+                "`$$${}` in `{}` would have to capture a run of nodes, which tree-sitter can't do. Use `$$$` or `$$$_{}` instead, or `${}` if it only ever stands for one node.",
+                name,
+                pattern,
+                name,
+                name,
+            )
+        }
+
+        // every anonymous metavariable is its own thing, so they each get
+        // a different placeholder
+        let distinct = if name.is_empty() || name.starts_with('_') {
+            format!("{}{}", name, metavariables.len())
+        } else {
+            name.to_string()
+        };
+        let placeholder = format!(
+            "{}{}",
+            if multiple {
+                MULTIPLE_PREFIX
+            } else {
+                SINGLE_PREFIX
+            },
+            distinct.to_ascii_lowercase()
+        );
+
+        let metavariable = if multiple {
+            Metavariable::Multiple
+        } else if name.starts_with('_') {
+            Metavariable::Single(None)
+        } else {
+            Metavariable::Single(Some(name.to_string()))
+        };
+
+        metavariables.insert(placeholder.clone(), metavariable);
+        code.push_str(&placeholder);
+        rest = &after_dollars[name_len..];
+    }
+
+    code.push_str(rest);
+
+    Ok((code, metavariables))
+}
+
+fn find_error(node: Node) -> Option<Node> {
+    if node.is_error() {
+        return Some(node);
+    }
+
+    let mut cursor = node.walk();
+    let found = node.children(&mut cursor).find_map(find_error);
+    found
+}
+
+/// Grammars wrap snippets in nodes for the whole file, statements, and so
+/// on. The pattern is the innermost node that still covers all of it.
+fn innermost<'tree>(mut node: Node<'tree>, code: &str) -> Node<'tree> {
+    loop {
+        let mut cursor = node.walk();
+        // tree-sitter counts ERROR nodes as extras, but we want to look
+        // inside them
+        let mut named = node
+            .named_children(&mut cursor)
+            .filter(|child| !child.is_extra() || child.is_error());
+
+        match (named.next(), named.next()) {
+            (Some(only), None) if covers(only, code) => node = only,
+            _ => return node,
+        }
+    }
+}
+
+fn covers(node: Node, code: &str) -> bool {
+    node.utf8_text(code.as_bytes())
+        .map(|text| text.trim() == code.trim())
+        .unwrap_or(false)
+}
+
+struct QueryBuilder<'a> {
+    source: &'a [u8],
+    metavariables: &'a HashMap<String, Metavariable>,
+    /// The metavariables we've captured once already. Later places they
+    /// appear have to have the same text.
+    seen: HashSet<String>,
+    predicates: Vec<String>,
+    next_internal: usize,
+    /// Whether there's a capture that will show up in the output
+    has_output: bool,
+}
+
+impl<'a> QueryBuilder<'a> {
+    fn text(&self, node: Node) -> &'a str {
+        node.utf8_text(self.source).unwrap_or_default()
+    }
+
+    fn metavariable(&self, node: Node) -> Option<&'a Metavariable> {
+        self.metavariables.get(self.text(node))
+    }
+
+    /// A capture that's only there for a predicate to look at.
+    fn internal_capture(&mut self) -> String {
+        self.next_internal += 1;
+        format!("_tg{}", self.next_internal)
+    }
+
+    fn node(&mut self, node: Node) -> String {
+        match self.metavariable(node) {
+            Some(Metavariable::Single(None)) => return "(_)".to_string(),
+            Some(Metavariable::Single(Some(name))) => {
+                if self.seen.insert(name.clone()) {
+                    self.has_output = true;
+                    return format!("(_) @{}", name);
+                }
+
+                let capture = self.internal_capture();
+                self.predicates
+                    .push(format!("(#eq? @{} @{})", name, capture));
+                return format!("(_) @{}", capture);
+            }
+            // these are handled by the parent, since they stand for
+            // siblings rather than a node
+            Some(Metavariable::Multiple) | None => {}
+        }
+
+        if !node.is_named() {
+            return quote(node.kind());
+        }
+
+        let mut cursor = node.walk();
+        let children: Vec<(Option<&'static str>, Node)> = node
+            .children(&mut cursor)
+            .enumerate()
+            .map(|(index, child)| (node.field_name_for_child(index as u32), child))
+            .filter(|(_, child)| !child.is_extra() && !child.is_missing())
+            .collect();
+
+        // leaves (including nodes like strings, whose only children are
+        // punctuation) match on their text
+        if !children.iter().any(|(_, child)| child.is_named()) {
+            let capture = self.internal_capture();
+            self.predicates.push(format!(
+                "(#eq? @{} {})",
+                capture,
+                quote(&self.original_text(node))
+            ));
+            return format!("({}) @{}", node.kind(), capture);
+        }
+
+        // We anchor children to each other so that the pattern matches
+        // exactly the children in the snippet, not just some of them.
+        // Anchors only care about named nodes, so unnamed children (like
+        // punctuation) are left out unless they're in a field, where they
+        // usually mean something (like the operator in `a + b`.)
+        let mut parts = Vec::new();
+        let mut anchor_next = true;
+        for (field, child) in children {
+            // `$$$` stands for any number of siblings, so we just leave a
+            // gap without an anchor
+            if let Some(Metavariable::Multiple) = self.metavariable(child) {
+                anchor_next = false;
+                continue;
+            }
+
+            if !child.is_named() && field.is_none() {
+                continue;
+            }
+
+            if child.is_named() && anchor_next {
+                parts.push(".".to_string());
+            }
+
+            let pattern = self.node(child);
+            parts.push(match field {
+                Some(field) => format!("{}: {}", field, pattern),
+                None => pattern,
+            });
+
+            if child.is_named() {
+                anchor_next = true;
+            }
+        }
+        if anchor_next {
+            parts.push(".".to_string());
+        }
+
+        format!("({} {})", node.kind(), parts.join(" "))
+    }
+
+    /// The text of `node` as it was in the pattern, with metavariables put
+    /// back the way they were written.
+    fn original_text(&self, node: Node) -> String {
+        let mut text = self.text(node).to_string();
+
+        // placeholders can be prefixes of each other (`$A` and `$AB` become
+        // `tgmetavar_a` and `tgmetavar_ab`) so the longest ones have to go
+        // first, or we'd put back `$A` followed by a stray `b`.
+        let mut placeholders: Vec<(&String, &Metavariable)> = self.metavariables.iter().collect();
+        placeholders.sort_by_key(|(placeholder, _)| std::cmp::Reverse(placeholder.len()));
+
+        for (placeholder, metavariable) in placeholders {
+            let original = match metavariable {
+                Metavariable::Single(Some(name)) => format!("${}", name),
+                Metavariable::Single(None) => "$_".to_string(),
+                Metavariable::Multiple => "$$$".to_string(),
+            };
+            text = text.replace(placeholder, &original);
+        }
+        text
+    }
+}
+
+/// Quote text as a string in a query.
+fn quote(text: &str) -> String {
+    let mut out = String::with_capacity(text.len() + 2);
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(all(test, feature = "bundled-grammars"))]
+mod tests {
+    use super::*;
+    use crate::language::{Aliases, Registry};
+
+    fn rust() -> Language {
+        Registry::new(Vec::new(), Vec::new(), Aliases::new())
+            .get("rust")
+            .unwrap()
+    }
+
+    #[test]
+    fn metavariables_that_are_prefixes_of_each_other() {
+        let query = to_query(&rust(), r#"f($A, $AB, "$A and $AB")"#).unwrap();
+
+        assert!(query.contains("@A"), "{}", query);
+        assert!(query.contains("@AB"), "{}", query);
+        assert!(query.contains(r#""\"$A and $AB\"""#), "{}", query);
+    }
+
+    #[test]
+    fn anonymous_runs() {
+        assert_eq!(
+            to_query(&rust(), "f($$$)").unwrap(),
+            to_query(&rust(), "f($$$_REST)").unwrap()
+        );
+    }
+
+    #[test]
+    fn named_runs_are_an_error() {
+        assert_eq!(
+            to_query(&rust(), "f($A, $$$REST)").unwrap_err().to_string(),
+            "`$$$REST` in `f($A, $$$REST)` would have to capture a run of nodes, which tree-sitter can't do. Use `$$$` or `$$$_REST` instead, or `$REST` if it only ever stands for one node."
+        );
+    }
+}
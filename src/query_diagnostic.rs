@@ -0,0 +1,186 @@
+use crate::language::Language;
+use itertools::Itertools;
+use std::error::Error;
+use std::fmt::{self, Display};
+use tree_sitter::{QueryError, QueryErrorKind};
+
+/// How similar a name has to be to the one in the query before we suggest
+/// it. This is the same cutoff clap uses for misspelled flags.
+const SIMILARITY_THRESHOLD: f64 = 0.8;
+
+/// The most suggestions we'll make for a single mistake.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// A query that didn't compile, explained: where the problem is (with a
+/// caret under it), what kind of problem it is, and what you might have
+/// meant instead.
+#[derive(Debug)]
+pub struct QueryDiagnostic {
+    language: String,
+    /// the line of the query with the problem
+    line: String,
+    row: usize,
+    multiline: bool,
+    column: usize,
+    width: usize,
+    kind: QueryErrorKind,
+    message: String,
+    suggestions: Vec<String>,
+}
+
+impl QueryDiagnostic {
+    pub fn new(language: &Language, source: &str, error: QueryError) -> QueryDiagnostic {
+        let line = source
+            .lines()
+            .nth(error.row)
+            .unwrap_or_default()
+            .to_string();
+
+        // for these kinds, the message is the name that was wrong, so we can
+        // underline all of it.
+        let names_something = matches!(
+            error.kind,
+            QueryErrorKind::NodeType | QueryErrorKind::Field | QueryErrorKind::Capture
+        );
+
+        let suggestions = match error.kind {
+            QueryErrorKind::NodeType => {
+                // a name in quotes is an anonymous node, like "fn"
+                let anonymous = source[..error.offset].ends_with('"');
+                closest(&error.message, node_kinds(language, !anonymous))
+            }
+            QueryErrorKind::Field => closest(&error.message, field_names(language)),
+            QueryErrorKind::Capture => closest(&error.message, capture_names(source)),
+            _ => Vec::new(),
+        };
+
+        QueryDiagnostic {
+            language: language.name().to_string(),
+            row: error.row,
+            multiline: source.trim_end().contains('\n'),
+            column: error.column,
+            width: if names_something {
+                error.message.chars().count().max(1)
+            } else {
+                1
+            },
+            line,
+            kind: error.kind,
+            message: error.message,
+            suggestions,
+        }
+    }
+
+    fn explanation(&self) -> String {
+        match self.kind {
+            QueryErrorKind::NodeType => format!(
+                "`{}` isn't a kind of node in the {} grammar.",
+                self.message, self.language
+            ),
+            QueryErrorKind::Field => format!(
+                "`{}` isn't a field in the {} grammar.",
+                self.message, self.language
+            ),
+            QueryErrorKind::Capture => format!(
+                "@{} is used in a predicate, but the pattern doesn't capture anything by that name.",
+                self.message
+            ),
+            QueryErrorKind::Structure => format!(
+                "this pattern can never match, because the {} grammar doesn't put nodes together this way. Try `tree-grepper --show-tree {} FILE` on some code to see how they fit.",
+                self.language, self.language
+            ),
+            QueryErrorKind::Predicate => format!("there's a problem with a predicate: {}", self.message),
+            QueryErrorKind::Language => self.message.clone(),
+            QueryErrorKind::Syntax => "this isn't valid query syntax. Check that parentheses, brackets, and quotes are balanced, and that predicates look like (#eq? @capture \"text\").".to_string(),
+        }
+    }
+}
+
+impl Display for QueryDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let QueryErrorKind::Language = self.kind {
+            return write!(f, "{}", self.explanation());
+        }
+
+        writeln!(f, "{}\n", self.explanation())?;
+
+        let gutter = if self.multiline {
+            format!("{} | ", self.row + 1)
+        } else {
+            String::new()
+        };
+
+        writeln!(f, "    {}{}", gutter, self.line)?;
+
+        // line the caret up with the line above, even if there are
+        // multi-byte characters before the problem
+        let before = self.line.get(..self.column).unwrap_or_default();
+        write!(
+            f,
+            "    {}{}{}",
+            " ".repeat(gutter.len()),
+            " ".repeat(before.chars().count()),
+            "^".repeat(self.width)
+        )?;
+
+        match self.suggestions.as_slice() {
+            [] => Ok(()),
+            [only] => write!(f, "\n\nDid you mean `{}`?", only),
+            many => write!(
+                f,
+                "\n\nDid you mean one of these? {}",
+                many.iter().map(|name| format!("`{}`", name)).join(", ")
+            ),
+        }
+    }
+}
+
+impl Error for QueryDiagnostic {}
+
+/// The names in `candidates` most like `name`, best first.
+fn closest(name: &str, candidates: impl Iterator<Item = String>) -> Vec<String> {
+    candidates
+        .unique()
+        .filter(|candidate| candidate != name)
+        .map(|candidate| (strsim::jaro_winkler(name, &candidate), candidate))
+        .filter(|(similarity, _)| *similarity >= SIMILARITY_THRESHOLD)
+        .sorted_by(|(a, _), (b, _)| b.total_cmp(a))
+        .take(MAX_SUGGESTIONS)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+fn node_kinds(language: &Language, named: bool) -> impl Iterator<Item = String> {
+    let ts_lang = language.ts_lang();
+
+    (0..ts_lang.node_kind_count() as u16)
+        .filter(move |id| ts_lang.node_kind_is_named(*id) == named)
+        .filter_map(move |id| ts_lang.node_kind_for_id(id))
+        .map(String::from)
+}
+
+fn field_names(language: &Language) -> impl Iterator<Item = String> {
+    let ts_lang = language.ts_lang();
+
+    // field IDs start at 1
+    (1..=ts_lang.field_count() as u16)
+        .filter_map(move |id| ts_lang.field_name_for_id(id))
+        .map(String::from)
+}
+
+/// Every capture in the query source. We can't ask the query, since it
+/// didn't compile, so we look for `@name` ourselves.
+fn capture_names(source: &str) -> impl Iterator<Item = String> + '_ {
+    source.split('@').skip(1).filter_map(|rest| {
+        let name: String = rest
+            .chars()
+            .take_while(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | '.'))
+            .collect();
+
+        if name.is_empty() {
+            None
+        } else {
+            Some(name)
+        }
+    })
+}
@@ -0,0 +1,965 @@
+use crate::language::Language;
+use crate::node_types::{describe, NodeTypes, TypeRef};
+use anyhow::Result;
+use itertools::Itertools;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::{self, Display};
+
+/// The most kinds we'll list when saying what's allowed somewhere. Past
+/// this, we point at `--node-types` instead.
+const MAX_LISTED: usize = 8;
+
+/// Finds patterns that compile but can't do what they look like they do:
+/// children or fields the grammar never produces, captures that don't go
+/// anywhere, and patterns that are given twice.
+///
+/// tree-sitter doesn't tell us anything about the structure of a compiled
+/// query, so we parse the patterns ourselves. Only call `check` with
+/// queries that have already compiled; we don't try to explain syntax
+/// errors here.
+pub struct Linter {
+    node_types: HashMap<String, NodeTypes>,
+    /// Every pattern we've seen so far for each language, normalized, with
+    /// where it came from.
+    seen: HashMap<String, Vec<(String, Location)>>,
+    /// Captures asked for with `--capture`, which are in the output even if
+    /// they start with an underscore.
+    shown: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+struct Location {
+    label: String,
+    row: usize,
+    column: usize,
+}
+
+/// Something wrong with a query, and where it is.
+#[derive(Debug)]
+pub struct Problem {
+    location: Location,
+    line: String,
+    width: usize,
+    message: String,
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "query {}, line {}, column {}: {}\n",
+            self.location.label,
+            self.location.row + 1,
+            self.location.column + 1,
+            self.message
+        )?;
+        writeln!(f, "    {}", self.line)?;
+        write!(
+            f,
+            "    {}{}",
+            " ".repeat(self.location.column),
+            "^".repeat(self.width)
+        )
+    }
+}
+
+impl Linter {
+    pub fn new() -> Linter {
+        Linter {
+            node_types: HashMap::new(),
+            seen: HashMap::new(),
+            shown: Vec::new(),
+        }
+    }
+
+    /// Don't complain about these captures starting with an underscore,
+    /// since `--capture` puts them in the output.
+    pub fn with_shown_captures(mut self, shown: Vec<String>) -> Linter {
+        self.shown = shown;
+        self
+    }
+
+    /// Check one query. Returns the problems we found, and a note if we
+    /// couldn't check everything.
+    pub fn check(
+        &mut self,
+        language: &Language,
+        label: &str,
+        source: &str,
+    ) -> Result<(Vec<Problem>, Option<String>)> {
+        if !self.node_types.contains_key(language.name()) {
+            self.node_types
+                .insert(language.name().to_string(), NodeTypes::new(language)?);
+        }
+        let node_types = &self.node_types[language.name()];
+
+        let patterns = match Parser::new(source).top_level() {
+            Some(patterns) => patterns,
+            None => {
+                return Ok((
+                    Vec::new(),
+                    Some(format!(
+                    "I couldn't make sense of query {} well enough to check it, but it compiles.",
+                    label
+                )),
+                ))
+            }
+        };
+
+        let mut found: Vec<(Span, String)> = Vec::new();
+
+        for top in &patterns {
+            if node_types.has_details() {
+                Checker {
+                    node_types,
+                    problems: &mut found,
+                }
+                .pattern(&top.pattern);
+            }
+
+            for (name, span) in &top.captures {
+                if name.starts_with('_')
+                    && !top.predicate_captures.contains(name)
+                    && !self.shown.contains(name)
+                {
+                    found.push((
+                        *span,
+                        format!("@{} isn't in the output (since it starts with an underscore) and no predicate uses it, so it doesn't do anything. Remove it, or drop the underscore to see what it captures.", name),
+                    ));
+                }
+            }
+        }
+
+        // we check for duplicates last so that problems come out in the
+        // order they appear in the query
+        let seen = self.seen.entry(language.name().to_string()).or_default();
+        for top in &patterns {
+            let normalized = normalize(&source[top.span.start..top.span.end]);
+            if let Some((_, first)) = seen.iter().find(|(other, _)| *other == normalized) {
+                found.push((
+                    top.span,
+                    format!(
+                        "this pattern is the same as the one in query {} at line {}, column {}, so everything it matches will be reported twice.",
+                        first.label,
+                        first.row + 1,
+                        first.column + 1
+                    ),
+                ));
+            } else {
+                seen.push((normalized, locate(source, label, top.span.start)));
+            }
+        }
+
+        found.sort_by_key(|(span, _)| span.start);
+
+        let note = if node_types.has_details() {
+            None
+        } else {
+            Some(format!(
+                "I couldn't find a node-types.json for {}, so I only checked query {}'s captures and looked for duplicate patterns. See `tree-grepper --node-types {}` for where to put it.",
+                node_types.language(),
+                label,
+                node_types.language()
+            ))
+        };
+
+        Ok((
+            found
+                .into_iter()
+                .map(|(span, message)| problem(source, label, span, message))
+                .collect(),
+            note,
+        ))
+    }
+}
+
+impl Default for Linter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn locate(source: &str, label: &str, offset: usize) -> Location {
+    let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+
+    Location {
+        label: label.to_string(),
+        row: source[..offset].matches('\n').count(),
+        column: source[line_start..offset].chars().count(),
+    }
+}
+
+fn problem(source: &str, label: &str, span: Span, message: String) -> Problem {
+    let location = locate(source, label, span.start);
+    let line = source.lines().nth(location.row).unwrap_or_default();
+
+    // only underline as far as the end of the line
+    let end_of_line = source[span.start..]
+        .find('\n')
+        .map_or(source.len(), |i| span.start + i);
+
+    Problem {
+        width: source[span.start..span.end.min(end_of_line)]
+            .chars()
+            .count()
+            .max(1),
+        line: line.to_string(),
+        location,
+        message,
+    }
+}
+
+/// Compare patterns without caring about whitespace or comments.
+fn normalize(pattern: &str) -> String {
+    let mut out = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars().peekable();
+    let mut pending_space = false;
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => {
+                if pending_space {
+                    out.push(' ');
+                    pending_space = false;
+                }
+                out.push(c);
+                while let Some(c) = chars.next() {
+                    out.push(c);
+                    match c {
+                        '\\' => out.extend(chars.next()),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            ';' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        break;
+                    }
+                }
+                pending_space = true;
+            }
+            c if c.is_whitespace() => pending_space = true,
+            c => {
+                if pending_space && !matches!(c, ')' | ']') && !out.ends_with(['(', '[']) {
+                    out.push(' ');
+                }
+                pending_space = false;
+                out.push(c);
+            }
+        }
+    }
+
+    out.trim().to_string()
+}
+
+/// Walks a parsed pattern and compares it to the grammar.
+struct Checker<'a> {
+    node_types: &'a NodeTypes,
+    problems: &'a mut Vec<(Span, String)>,
+}
+
+impl<'a> Checker<'a> {
+    fn pattern(&mut self, pattern: &Pattern) {
+        match pattern {
+            Pattern::Node {
+                kind,
+                children,
+                negated_fields,
+                ..
+            } => {
+                for child in children {
+                    self.child(kind.as_deref(), child);
+                }
+
+                for (field, span) in negated_fields {
+                    if let Some(parent) = kind.as_deref() {
+                        if let Some(message) = self.missing_field(parent, field) {
+                            self.problems.push((
+                                *span,
+                                format!("{}, so `!{}` is always true.", message, field),
+                            ));
+                        }
+                    }
+                }
+            }
+            Pattern::Alternation(alternatives) => {
+                for alternative in alternatives {
+                    self.pattern(&alternative.pattern);
+                }
+            }
+            Pattern::Group(children) => {
+                for child in children {
+                    self.pattern(&child.pattern);
+                }
+            }
+            Pattern::Anonymous { .. } | Pattern::Predicate => {}
+        }
+    }
+
+    /// Check a child pattern against its parent's kind. `parent` is `None`
+    /// for wildcards, which can have any children.
+    fn child(&mut self, parent: Option<&str>, child: &Child) {
+        match &child.pattern {
+            Pattern::Group(grouped) => {
+                for grouped_child in grouped {
+                    self.child(parent, grouped_child);
+                }
+                return;
+            }
+            // `[name: (a) body: (b)]` gives each alternative its own field
+            Pattern::Alternation(alternatives)
+                if alternatives
+                    .iter()
+                    .any(|alternative| alternative.field.is_some()) =>
+            {
+                for alternative in alternatives {
+                    self.child(parent, alternative);
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        self.pattern(&child.pattern);
+
+        let parent = match parent {
+            Some(parent) => parent,
+            None => return,
+        };
+
+        // ERROR and MISSING nodes, and anything else node-types.json
+        // doesn't describe, could have anything in them.
+        let info = match self.node_types.kind(parent) {
+            Some(info) => info,
+            None => return,
+        };
+
+        match &child.field {
+            Some((field, field_span)) => {
+                if let Some(message) = self.missing_field(parent, field) {
+                    self.problems.push((
+                        *field_span,
+                        format!("{}, so this pattern can never match.", message),
+                    ));
+                    return;
+                }
+
+                let allowed = &info.fields[field].types;
+                for (kind, span) in child.pattern.kinds() {
+                    if !self.allows(allowed, &kind) {
+                        self.problems.push((
+                            span,
+                            format!(
+                                "{} can never be the `{}` of a `{}`. {}",
+                                show(&kind),
+                                field,
+                                parent,
+                                self.list("It can be", allowed)
+                            ),
+                        ));
+                    }
+                }
+            }
+            None => {
+                let allowed = info.all_child_types();
+                for (kind, span) in child.pattern.kinds() {
+                    // node-types.json doesn't list anonymous children
+                    // outside of fields, so we can't check those.
+                    if !kind.named || self.allows(&allowed, &kind) {
+                        continue;
+                    }
+
+                    // extras (like comments) can show up anywhere
+                    if !self.node_types.is_ever_a_child(&kind) {
+                        continue;
+                    }
+
+                    let message = if allowed.is_empty() {
+                        format!(
+                            "{} can never be inside a `{}`, since it doesn't have any children.",
+                            show(&kind),
+                            parent
+                        )
+                    } else {
+                        format!(
+                            "{} can never be inside a `{}`. {}",
+                            show(&kind),
+                            parent,
+                            self.list("It can contain", &allowed)
+                        )
+                    };
+                    self.problems.push((span, message));
+                }
+            }
+        }
+    }
+
+    /// If `parent` doesn't have `field`, say so (without ending the
+    /// sentence, since what that means depends on how it's used.)
+    fn missing_field(&self, parent: &str, field: &str) -> Option<String> {
+        let info = self.node_types.kind(parent)?;
+        if info.fields.contains_key(field) {
+            return None;
+        }
+
+        Some(if info.fields.is_empty() {
+            format!("`{}` doesn't have any fields", parent)
+        } else {
+            format!(
+                "`{}` doesn't have a field called `{}`, only {}",
+                parent,
+                field,
+                info.fields
+                    .keys()
+                    .map(|field| format!("`{}`", field))
+                    .join(", ")
+            )
+        })
+    }
+
+    fn allows(&self, allowed: &[TypeRef], kind: &TypeRef) -> bool {
+        let wanted = self.node_types.expand(kind);
+        allowed
+            .iter()
+            .any(|candidate| !self.node_types.expand(candidate).is_disjoint(&wanted))
+    }
+
+    fn list(&self, prefix: &str, allowed: &[TypeRef]) -> String {
+        if allowed.len() <= MAX_LISTED {
+            format!("{}: {}", prefix, describe(allowed))
+        } else {
+            format!(
+                "Run `tree-grepper --node-types {}` to see what fits here.",
+                self.node_types.language()
+            )
+        }
+    }
+}
+
+fn show(kind: &TypeRef) -> String {
+    if kind.named {
+        format!("`{}`", kind.kind)
+    } else {
+        format!("`{:?}`", kind.kind)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Span {
+    start: usize,
+    end: usize,
+}
+
+/// A pattern at the top level of a query, along with the captures in it and
+/// the ones its predicates use.
+struct TopLevel {
+    pattern: Pattern,
+    span: Span,
+    captures: Vec<(String, Span)>,
+    predicate_captures: BTreeSet<String>,
+}
+
+enum Pattern {
+    /// `(kind children...)`. `kind` is `None` for `(_)`.
+    Node {
+        kind: Option<String>,
+        kind_span: Span,
+        children: Vec<Child>,
+        negated_fields: Vec<(String, Span)>,
+    },
+    /// `"text"`, or `_` (as `None`)
+    Anonymous { kind: Option<String>, span: Span },
+    /// `[a b c]`. Each alternative can have its own field.
+    Alternation(Vec<Child>),
+    /// `((a) (b))`: siblings, in order
+    Group(Vec<Child>),
+    /// `(#eq? @a "b")`
+    Predicate,
+}
+
+struct Child {
+    field: Option<(String, Span)>,
+    pattern: Pattern,
+}
+
+impl Pattern {
+    /// The kinds of node this pattern could match, for checking against
+    /// its parent. Wildcards and groups match nothing in particular.
+    fn kinds(&self) -> Vec<(TypeRef, Span)> {
+        match self {
+            Pattern::Node {
+                kind: Some(kind),
+                kind_span,
+                ..
+            } if kind != "ERROR" && kind != "MISSING" => vec![(
+                TypeRef {
+                    kind: kind.clone(),
+                    named: true,
+                },
+                *kind_span,
+            )],
+            Pattern::Anonymous {
+                kind: Some(kind),
+                span,
+            } => vec![(
+                TypeRef {
+                    kind: kind.clone(),
+                    named: false,
+                },
+                *span,
+            )],
+            Pattern::Alternation(alternatives) => alternatives
+                .iter()
+                .flat_map(|alternative| alternative.pattern.kinds())
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Just enough of a query parser to see the structure of patterns. Returns
+/// `None` from anything it doesn't understand.
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+    captures: Vec<(String, Span)>,
+    predicate_captures: BTreeSet<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(source: &'a str) -> Parser<'a> {
+        Parser {
+            source,
+            pos: 0,
+            captures: Vec::new(),
+            predicate_captures: BTreeSet::new(),
+        }
+    }
+
+    fn top_level(mut self) -> Option<Vec<TopLevel>> {
+        let mut out = Vec::new();
+
+        loop {
+            self.skip_trivia();
+            if self.pos >= self.source.len() {
+                return Some(out);
+            }
+
+            let start = self.pos;
+            let pattern = self.pattern()?;
+            out.push(TopLevel {
+                pattern,
+                span: Span {
+                    start,
+                    end: self.pos,
+                },
+                captures: std::mem::take(&mut self.captures),
+                predicate_captures: std::mem::take(&mut self.predicate_captures),
+            });
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.source[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn eat(&mut self, expected: char) -> Option<()> {
+        if self.peek() == Some(expected) {
+            self.bump();
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn skip_trivia(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() {
+                self.bump();
+            } else if c == ';' {
+                while !matches!(self.bump(), Some('\n') | None) {}
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn identifier(&mut self) -> Option<(String, Span)> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            if c.is_alphanumeric() || matches!(c, '_' | '-' | '.' | '?' | '!') {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+
+        if self.pos == start {
+            None
+        } else {
+            Some((
+                self.source[start..self.pos].to_string(),
+                Span {
+                    start,
+                    end: self.pos,
+                },
+            ))
+        }
+    }
+
+    fn string(&mut self) -> Option<String> {
+        self.eat('"')?;
+
+        let mut out = String::new();
+        loop {
+            match self.bump()? {
+                '"' => return Some(out),
+                '\\' => out.push(match self.bump()? {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    '0' => '\0',
+                    other => other,
+                }),
+                other => out.push(other),
+            }
+        }
+    }
+
+    fn pattern(&mut self) -> Option<Pattern> {
+        self.skip_trivia();
+        let start = self.pos;
+
+        let pattern = match self.peek()? {
+            '(' => {
+                self.bump();
+                self.skip_trivia();
+                match self.peek()? {
+                    '#' => self.predicate()?,
+                    '(' | '[' | '"' | '.' => Pattern::Group(self.children()?),
+                    _ => self.node()?,
+                }
+            }
+            '[' => {
+                self.bump();
+                let mut alternatives = Vec::new();
+                loop {
+                    self.skip_trivia();
+                    if self.eat(']').is_some() {
+                        break;
+                    }
+                    alternatives.push(self.child()?);
+                }
+                Pattern::Alternation(alternatives)
+            }
+            '"' => {
+                let kind = self.string()?;
+                Pattern::Anonymous {
+                    kind: Some(kind),
+                    span: Span {
+                        start,
+                        end: self.pos,
+                    },
+                }
+            }
+            '_' => {
+                self.bump();
+                Pattern::Anonymous {
+                    kind: None,
+                    span: Span {
+                        start,
+                        end: self.pos,
+                    },
+                }
+            }
+            _ => return None,
+        };
+
+        // quantifiers and captures can come in any order after a pattern
+        loop {
+            let end = self.pos;
+            self.skip_trivia();
+            match self.peek() {
+                Some('*' | '+' | '?') => {
+                    self.bump();
+                }
+                Some('@') => {
+                    let at = self.pos;
+                    self.bump();
+                    let (name, span) = self.identifier()?;
+                    self.captures.push((
+                        name,
+                        Span {
+                            start: at,
+                            end: span.end,
+                        },
+                    ));
+                }
+                _ => {
+                    // leave any whitespace or comments after the pattern
+                    // out of its span
+                    self.pos = end;
+                    return Some(pattern);
+                }
+            }
+        }
+    }
+
+    /// The rest of `(kind children...)`, after the opening paren.
+    fn node(&mut self) -> Option<Pattern> {
+        let (mut kind, mut kind_span) = self.identifier()?;
+
+        // `(supertype/subtype)` matches the subtype
+        if self.eat('/').is_some() {
+            (kind, kind_span) = self.identifier()?;
+        }
+
+        if kind == "MISSING" {
+            self.skip_trivia();
+            if self.peek() == Some('"') {
+                self.string()?;
+            } else {
+                self.identifier();
+            }
+        }
+
+        let mut children = Vec::new();
+        let mut negated_fields = Vec::new();
+        loop {
+            self.skip_trivia();
+            match self.peek()? {
+                ')' => {
+                    self.bump();
+                    break;
+                }
+                '.' => {
+                    self.bump();
+                }
+                '!' => {
+                    let start = self.pos;
+                    self.bump();
+                    let (field, span) = self.identifier()?;
+                    negated_fields.push((
+                        field,
+                        Span {
+                            start,
+                            end: span.end,
+                        },
+                    ));
+                }
+                _ => children.push(self.child()?),
+            }
+        }
+
+        Some(Pattern::Node {
+            kind: if kind == "_" { None } else { Some(kind) },
+            kind_span,
+            children,
+            negated_fields,
+        })
+    }
+
+    /// The rest of a group, after the opening paren.
+    fn children(&mut self) -> Option<Vec<Child>> {
+        let mut out = Vec::new();
+        loop {
+            self.skip_trivia();
+            match self.peek()? {
+                ')' => {
+                    self.bump();
+                    return Some(out);
+                }
+                '.' => {
+                    self.bump();
+                }
+                _ => out.push(self.child()?),
+            }
+        }
+    }
+
+    fn child(&mut self) -> Option<Child> {
+        let before = self.pos;
+        if let Some((name, span)) = self.identifier() {
+            self.skip_trivia();
+            if self.eat(':').is_some() {
+                return Some(Child {
+                    field: Some((name, span)),
+                    pattern: self.pattern()?,
+                });
+            }
+        }
+
+        self.pos = before;
+        Some(Child {
+            field: None,
+            pattern: self.pattern()?,
+        })
+    }
+
+    /// The rest of `(#predicate? args...)`, after the opening paren. We
+    /// only care about which captures it uses.
+    fn predicate(&mut self) -> Option<Pattern> {
+        self.eat('#')?;
+        self.identifier()?;
+
+        loop {
+            self.skip_trivia();
+            match self.peek()? {
+                ')' => {
+                    self.bump();
+                    return Some(Pattern::Predicate);
+                }
+                '"' => {
+                    self.string()?;
+                }
+                '@' => {
+                    self.bump();
+                    let (name, _) = self.identifier()?;
+                    self.predicate_captures.insert(name);
+                }
+                _ => {
+                    self.identifier()?;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "bundled-grammars"))]
+mod tests {
+    use super::*;
+    use crate::language::{Aliases, Registry};
+
+    const STRUCT_ITEM_CHILDREN: &str = "`integer_literal` can never be inside a `struct_item`. It can contain: field_declaration_list | ordered_field_declaration_list | type_identifier | type_parameters | visibility_modifier | where_clause";
+
+    fn rust() -> Language {
+        Registry::new(Vec::new(), Vec::new(), Aliases::new())
+            .get("rust")
+            .unwrap()
+    }
+
+    /// Lint a query that compiles, and get the messages for each problem.
+    fn messages(linter: &mut Linter, source: &str) -> Vec<String> {
+        let rust = rust();
+        rust.query(source).unwrap();
+
+        let (problems, note) = linter.check(&rust, "1", source).unwrap();
+        assert_eq!(note, None);
+        problems
+            .into_iter()
+            .map(|problem| problem.message)
+            .collect()
+    }
+
+    fn check(source: &str) -> Vec<String> {
+        messages(&mut Linter::new(), source)
+    }
+
+    #[test]
+    fn alternations() {
+        assert_eq!(
+            check("(function_item name: [(identifier) (integer_literal)])"),
+            vec!["`integer_literal` can never be the `name` of a `function_item`. It can be: identifier | metavariable"]
+        );
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(
+            check("(struct_item ((type_identifier) [(type_identifier) (integer_literal)]))"),
+            vec![STRUCT_ITEM_CHILDREN]
+        );
+        assert!(check("((function_item) @function . (line_comment) @comment)").is_empty());
+    }
+
+    #[test]
+    fn quantifiers() {
+        assert_eq!(
+            check("(struct_item [(type_identifier) (integer_literal)]* @child)"),
+            vec![STRUCT_ITEM_CHILDREN]
+        );
+        assert!(
+            check("(function_item (visibility_modifier)? name: (identifier) @name)").is_empty()
+        );
+    }
+
+    #[test]
+    fn predicates() {
+        assert!(check(
+            r#"(function_item name: (identifier) @_name (#not-eq? @_name "main")) @function"#
+        )
+        .is_empty());
+        assert!(check(r#"((identifier) @_id (#match? @_id "^x") (#eq? @_id "xs"))"#).is_empty());
+        assert_eq!(
+            check("[(identifier) @_id (integer_literal) @number]"),
+            vec!["@_id isn't in the output (since it starts with an underscore) and no predicate uses it, so it doesn't do anything. Remove it, or drop the underscore to see what it captures."]
+        );
+    }
+
+    #[test]
+    fn captures_asked_for_with_capture() {
+        let mut linter = Linter::new().with_shown_captures(vec!["_id".to_string()]);
+        assert!(messages(&mut linter, "(identifier) @_id").is_empty());
+    }
+
+    #[test]
+    fn anchors() {
+        assert_eq!(
+            check("(struct_item . [(type_identifier) (integer_literal)] .)"),
+            vec![STRUCT_ITEM_CHILDREN]
+        );
+        assert!(check("(block . (expression_statement) @first)").is_empty());
+    }
+
+    #[test]
+    fn missing() {
+        // tree-sitter 0.20 can't compile queries for MISSING nodes, but
+        // newer versions can, so make sure we don't choke on them.
+        let rust = rust();
+        let mut linter = Linter::new();
+        for source in [
+            r#"(MISSING ";")"#,
+            "(MISSING identifier)",
+            "(function_item (MISSING))",
+        ] {
+            let (problems, note) = linter.check(&rust, "1", source).unwrap();
+            assert!(problems.is_empty(), "{:?}", problems);
+            assert_eq!(note, None);
+        }
+    }
+
+    #[test]
+    fn supertypes() {
+        assert_eq!(
+            check("(function_item name: [(identifier) (_expression/integer_literal)])"),
+            vec!["`integer_literal` can never be the `name` of a `function_item`. It can be: identifier | metavariable"]
+        );
+        assert!(check("(function_item name: [(identifier) (_expression)])").is_empty());
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(
+            check("(function_item [name: (identifier) body: (integer_literal)])"),
+            vec![
+                "`integer_literal` can never be the `body` of a `function_item`. It can be: block"
+            ]
+        );
+        assert_eq!(
+            check("(struct_item name: (type_identifier) !return_type)"),
+            vec!["`struct_item` doesn't have a field called `return_type`, only `body`, `name`, `type_parameters`, so `!return_type` is always true."]
+        );
+        assert_eq!(
+            check("(identifier !name)"),
+            vec!["`identifier` doesn't have any fields, so `!name` is always true."]
+        );
+    }
+}
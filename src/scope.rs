@@ -0,0 +1,51 @@
+use crate::language::Language;
+use anyhow::{bail, Result};
+use itertools::Itertools;
+
+/// Turn the SCOPE in `--in LANGUAGE SCOPE REGEX` into a query. A scope
+/// that looks like a query (it starts with `(` or `[`) is used as-is, and
+/// the regex is searched for in everything it captures. Otherwise, it's a
+/// kind of node, captured with the scope as the name.
+///
+/// Grammars don't agree on what to call things (Rust has `line_comment`
+/// and `block_comment`, where most grammars have `comment`) so if there's
+/// no kind with exactly the scope's name, we use every named kind that has
+/// it as one of the words in its name.
+pub fn to_query(language: &Language, scope: &str) -> Result<String> {
+    let trimmed = scope.trim();
+    if trimmed.starts_with('(') || trimmed.starts_with('[') {
+        return Ok(scope.to_string());
+    }
+
+    let ts_lang = language.ts_lang();
+    let kinds: Vec<&str> = (0..ts_lang.node_kind_count() as u16)
+        .filter(|id| ts_lang.node_kind_is_named(*id) && ts_lang.node_kind_is_visible(*id))
+        .filter_map(|id| ts_lang.node_kind_for_id(id))
+        .unique()
+        .collect();
+
+    if kinds.contains(&trimmed) {
+        return Ok(format!("({}) @{}", trimmed, trimmed));
+    }
+
+    let similar: Vec<&str> = kinds
+        .into_iter()
+        .filter(|kind| kind.split('_').any(|word| word == trimmed))
+        .sorted()
+        .collect();
+
+    if similar.is_empty() {
+        bail!(
+            "`{}` isn't a kind of node in the {} grammar, and no kind has it as part of its name. Run `tree-grepper --node-types {}` to see the kinds there are, or give a query instead.",
+            trimmed,
+            language.name(),
+            language.name(),
+        )
+    }
+
+    Ok(format!(
+        "[{}] @{}",
+        similar.iter().map(|kind| format!("({})", kind)).join(" "),
+        trimmed
+    ))
+}